@@ -0,0 +1,128 @@
+//! An HTTP listener serving operational endpoints for the service
+
+use std::{convert::Infallible, net::SocketAddr};
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+
+use crate::{health::Health, logging::LogFilter, prelude::*};
+
+/// Shared state for handling admin requests
+#[derive(Debug, Clone)]
+pub(crate) struct State {
+    pub health: Health,
+    pub log_filter: LogFilter,
+    #[cfg(feature = "metrics")]
+    pub registry: crate::metrics::Registry,
+}
+
+/// Bind the admin listener to the given address
+///
+/// This must be called from within the context of a Tokio runtime.
+pub(crate) fn bind(
+    addr: SocketAddr,
+    state: State,
+) -> Result<impl Future<Output = Result<()>> + Send + 'static> {
+    let server = hyper::Server::try_bind(&addr)
+        .with_context(|| format!("Failed to bind admin listener to {addr}"))?
+        .serve(make_service_fn(move |_| {
+            let state = state.clone();
+
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        }));
+
+    info!(%addr, "Admin listener bound");
+
+    Ok(server.map_err(|e| anyhow::Error::new(e).context("Admin listener failed")))
+}
+
+async fn handle(state: State, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::GET, "/health") => text(StatusCode::OK, "ok"),
+        (&Method::GET, "/ready") => {
+            let report = state.health.check().await;
+
+            json(
+                if report.is_ready() {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                },
+                &report.to_json(),
+            )
+        },
+        #[cfg(feature = "metrics")]
+        (&Method::GET, "/metrics") => match crate::metrics::encode(&state.registry) {
+            Ok((content_type, body)) => {
+                let mut res = text(StatusCode::OK, body);
+                if let Ok(val) = header::HeaderValue::from_str(&content_type) {
+                    res.headers_mut().insert(header::CONTENT_TYPE, val);
+                }
+                res
+            },
+            Err(e) => {
+                error!("{e:?}");
+                text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to encode metrics",
+                )
+            },
+        },
+        (&Method::GET, "/log-filter") => match state.log_filter.current() {
+            Ok(filter) => text(StatusCode::OK, filter),
+            Err(e) => {
+                error!("{e:?}");
+                text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to read log filter",
+                )
+            },
+        },
+        (&Method::PUT, "/log-filter") => {
+            let res = hyper::body::to_bytes(req.into_body())
+                .await
+                .context("Failed to read request body")
+                .and_then(|b| String::from_utf8(b.to_vec()).context("Log filter was not UTF-8"))
+                .and_then(|f| state.log_filter.set(f.trim()));
+
+            log_filter_updated(&state.log_filter, res)
+        },
+        (&Method::DELETE, "/log-filter") => {
+            log_filter_updated(&state.log_filter, state.log_filter.reset())
+        },
+        (_, "/health" | "/ready" | "/metrics" | "/log-filter") => {
+            text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+        },
+        _ => text(StatusCode::NOT_FOUND, "Not found"),
+    })
+}
+
+fn log_filter_updated(log_filter: &LogFilter, res: Result<()>) -> Response<Body> {
+    match res.and_then(|()| log_filter.current()) {
+        Ok(filter) => text(StatusCode::OK, filter),
+        Err(e) => text(StatusCode::BAD_REQUEST, format!("{e:#}")),
+    }
+}
+
+fn text(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut res = Response::new(body.into());
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    res
+}
+
+fn json(status: StatusCode, body: &serde_json::Value) -> Response<Body> {
+    let mut res = Response::new(body.to_string().into());
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    res
+}
@@ -0,0 +1,136 @@
+//! The transport carrying records between producers and consumers
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use backon::ExponentialBuilder;
+use futures_util::Stream;
+use rdkafka::{
+    message::{OwnedHeaders, OwnedMessage, Timestamp},
+    Message as _,
+};
+use tokio::sync::broadcast;
+
+use crate::{kafka::ClientConfig, prelude::*};
+
+/// Broker list selecting the in-memory bus instead of Kafka
+pub(crate) const MEMORY_BROKERS: &str = "memory://";
+
+/// Number of records buffered for each in-memory consumer before the oldest
+/// records are dropped
+const MEMORY_BUS_CAPACITY: usize = 4096;
+
+/// The transport used by the producers and consumers of a service
+#[derive(Debug, Clone)]
+pub(crate) enum Bus {
+    /// Records are exchanged through the configured Kafka brokers
+    Kafka(ClientConfig),
+    /// Records are broadcast to consumers within this process
+    Memory(MemoryBus),
+    /// Kafka was disabled for the command being run
+    Disabled,
+}
+
+impl Bus {
+    /// Adjust the backoff used to retry connecting to Kafka during boot, if
+    /// this bus uses Kafka
+    pub fn boot_backoff(&mut self, f: impl FnOnce(ExponentialBuilder) -> ExponentialBuilder) {
+        match self {
+            Self::Kafka(config) => {
+                config.boot_retry.backoff = f(std::mem::take(&mut config.boot_retry.backoff));
+            },
+            Self::Memory(_) | Self::Disabled => (),
+        }
+    }
+}
+
+/// A stream of records received from an in-memory bus
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+pub(crate) type MemoryStream = Pin<Box<dyn Stream<Item = OwnedMessage> + Send>>;
+
+/// An in-process stand-in for Kafka, for local development without a running
+/// broker
+///
+/// Every consumer receives every record sent to the topics it subscribes to,
+/// as if each were in its own consumer group.  Records are not persisted, so
+/// records sent before a consumer subscribes are never delivered to it.
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryBus {
+    tx: broadcast::Sender<OwnedMessage>,
+    offsets: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(MEMORY_BUS_CAPACITY);
+
+        Self {
+            tx,
+            offsets: Arc::default(),
+        }
+    }
+}
+
+impl MemoryBus {
+    /// Send a record to all consumers subscribed to the given topic,
+    /// returning its partition and offset
+    pub fn send(
+        &self,
+        topic: &str,
+        payload: Option<Vec<u8>>,
+        key: Option<Vec<u8>>,
+        headers: OwnedHeaders,
+    ) -> (i32, i64) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|t| t.as_millis().try_into().ok())
+            .map_or(Timestamp::NotAvailable, Timestamp::CreateTime);
+
+        // The lock is held while sending so records arrive in offset order
+        let mut offsets = self.offsets.lock().unwrap_or_else(PoisonError::into_inner);
+        let next = offsets.entry(topic.to_owned()).or_default();
+        let offset = *next;
+        *next += 1;
+
+        // Records sent while no consumers are subscribed are dropped
+        self.tx
+            .send(OwnedMessage::new(
+                payload,
+                key,
+                topic.to_owned(),
+                timestamp,
+                0,
+                offset,
+                Some(headers),
+            ))
+            .ok();
+
+        (0, offset)
+    }
+
+    /// Open a stream of all records subsequently sent to the given topics
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    pub fn subscribe(&self, topics: &'static [&'static str]) -> MemoryStream {
+        futures_util::stream::unfold(self.tx.subscribe(), move |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(msg) if topics.contains(&msg.topic()) => break Some((msg, rx)),
+                    Ok(_) => (),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!(
+                            dropped = n,
+                            "In-memory consumer fell behind, dropping records"
+                        );
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
@@ -1,14 +1,23 @@
 //! A Kafka record consumer
 
-use std::{error::Error, fmt};
+use std::{
+    error::Error,
+    fmt,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
 
 use backon::{BackoffBuilder, ExponentialBuilder};
 use futures_util::Stream;
 use rdkafka::consumer::{Consumer as _, StreamConsumer};
-pub use rdkafka::Message;
+pub use rdkafka::{message::Headers, Message};
+use tracing::Instrument;
 
 use crate::{
+    bus::{Bus, MemoryBus, MemoryStream},
+    kafka::Context,
     prelude::*,
+    shutdown::Shutdown,
     triage::{Severity, Triage},
     util::DebugShim,
 };
@@ -17,49 +26,128 @@ use crate::{
 #[derive(Debug)]
 pub struct Config {
     pub(crate) service_name: String,
-    pub(crate) config: DebugShim<rdkafka::ClientConfig>,
+    pub(crate) bus: Bus,
+    pub(crate) shutdown: Shutdown,
 }
 
 impl Config {
     /// Construct a new record consumer from this config instance
     ///
     /// # Errors
-    /// This function returns an error if the Kafka consumer cannot be created
-    /// or if subscribing to the requested topics fails.
+    /// This function returns an error if the Kafka consumer cannot be created,
+    /// if subscribing to the requested topics fails, or if the brokers cannot
+    /// be reached before the boot timeout elapses.
     #[inline]
     pub async fn build<G: MessageGroup>(self) -> Result<Consumer<G>> {
         Consumer::new(self).await
     }
+
+    /// Adjust the backoff used to retry connecting to Kafka while building
+    /// the consumer
+    #[must_use]
+    pub fn boot_backoff(
+        mut self,
+        f: impl FnOnce(ExponentialBuilder) -> ExponentialBuilder,
+    ) -> Self {
+        self.bus.boot_backoff(f);
+        self
+    }
+
+    /// Check that every topic requested by the given message group exists on
+    /// the Kafka brokers
+    ///
+    /// Topics always exist on the in-memory bus, so this check always passes
+    /// when it is in use or Kafka is disabled.
+    ///
+    /// # Errors
+    /// This method returns an error if the broker metadata cannot be fetched
+    /// or any requested topic does not exist.
+    pub async fn check<G: MessageGroup>(&self) -> Result<()> {
+        let config = match self.bus {
+            Bus::Kafka(ref c) => c,
+            Bus::Memory(_) | Bus::Disabled => return Ok(()),
+        };
+        let admin = config
+            .create_admin()
+            .context("Failed to create Kafka admin client")?;
+
+        let topics = tokio::task::spawn_blocking(move || {
+            admin
+                .inner()
+                .fetch_metadata(None, Duration::from_secs(10))
+                .map(|m| {
+                    m.topics()
+                        .iter()
+                        .map(|t| t.name().to_owned())
+                        .collect::<Vec<_>>()
+                })
+        })
+        .await
+        .context("Failed to join Kafka metadata request")?
+        .context("Failed to fetch Kafka broker metadata")?;
+
+        let missing: Vec<_> = G::REQUESTED_TOPICS
+            .iter()
+            .filter(|t| !topics.iter().any(|n| n == *t))
+            .collect();
+        ensure!(missing.is_empty(), "Missing Kafka topics: {missing:?}");
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Backend {
+    Kafka(DebugShim<StreamConsumer<Context>>),
+    Memory(MemoryBus),
 }
 
 /// A consumer for requesting, receiving, and parsing messages from one or more
 /// Kafka topics
 #[derive(Debug)]
 pub struct Consumer<G> {
-    consumer: DebugShim<StreamConsumer>,
+    backend: Backend,
+    shutdown: Shutdown,
     group: PhantomData<fn() -> ConsumerStream<'static, G>>,
 }
 
 impl<G: MessageGroup> Consumer<G> {
     #[instrument(name = "build_consumer")]
-    pub(crate) async fn new(mut config: Config) -> Result<Self> {
-        let consumer: StreamConsumer = config
-            .config
-            .0
-            .set(
-                "group.id",
-                format!("{}@{}", std::any::type_name::<G>(), config.service_name),
-            )
-            .create()
-            .context("Failed to create Kafka consumer")?;
-
-        // TODO: backoff and retry for initial boot
+    pub(crate) async fn new(config: Config) -> Result<Self> {
+        let Config {
+            service_name,
+            bus,
+            shutdown,
+        } = config;
+
+        let mut config = match bus {
+            Bus::Kafka(c) => c,
+            Bus::Memory(bus) => {
+                return Ok(Self {
+                    backend: Backend::Memory(bus),
+                    shutdown,
+                    group: PhantomData::default(),
+                });
+            },
+            Bus::Disabled => bail!("Kafka is not initialized for this command"),
+        };
+
+        config.set(
+            "group.id",
+            format!("{}@{}", std::any::type_name::<G>(), service_name),
+        );
+
+        let consumer: StreamConsumer<_> =
+            config.create().context("Failed to create Kafka consumer")?;
         consumer
             .subscribe(G::REQUESTED_TOPICS)
             .context("Failed to subscribe consumer to requested topics")?;
 
+        config.await_brokers(G::REQUESTED_TOPICS).await?;
+
         Ok(Self {
-            consumer: DebugShim(consumer),
+            backend: Backend::Kafka(DebugShim(consumer)),
+            shutdown,
             group: PhantomData::default(),
         })
     }
@@ -79,19 +167,34 @@ impl<G: MessageGroup> Consumer<G> {
     #[must_use]
     #[inline]
     pub unsafe fn to_stream(&self) -> ConsumerStream<G> {
+        let stream = match self.backend {
+            Backend::Kafka(ref c) => Inner::Kafka {
+                stream: c.0.stream(),
+            },
+            Backend::Memory(ref b) => Inner::Memory {
+                stream: b.subscribe(G::REQUESTED_TOPICS),
+            },
+        };
+
         ConsumerStream {
-            stream: self.consumer.0.stream(),
+            stream,
             group: PhantomData::default(),
         }
     }
 
     /// Acquire a stream of incoming events and pass them to the given closure
     ///
+    /// Each handler runs in a `consume_message` span.  If trace export is
+    /// enabled, the span is a child of the span that produced the record, as
+    /// read from its headers by the global OpenTelemetry propagator.
+    ///
+    /// This method returns once a graceful shutdown has been requested and all
+    /// in-flight handlers have completed.
+    ///
     /// # Panics
     /// This method will immediately abort the process if the message
     /// stream returns too many errors, if handling an event results in a
     /// fatal error, or if a handler task panics.
-    // TODO: use the never ! type here
     pub async fn consume<
         B: FnOnce(ExponentialBuilder) -> ExponentialBuilder,
         H: FnOnce(G) -> F + Clone + Send + 'static,
@@ -101,10 +204,34 @@ impl<G: MessageGroup> Consumer<G> {
         &self,
         handler_backoff: B,
         handle: H,
-    ) -> std::convert::Infallible
-    where
+    ) where
+        G: Clone + Send + 'static,
+    {
+        self.shutdown
+            .track(self.consume_until_shutdown(handler_backoff, handle))
+            .await;
+    }
+
+    async fn consume_until_shutdown<
+        B: FnOnce(ExponentialBuilder) -> ExponentialBuilder,
+        H: FnOnce(G) -> F + Clone + Send + 'static,
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: Error + Send + Sync + Triage + 'static,
+    >(
+        &self,
+        handler_backoff: B,
+        handle: H,
+    ) where
         G: Clone + Send + 'static,
     {
+        const FATAL: &str = "Fatal error encountered in consumer loop!";
+
+        async fn abort(reason: &str) -> ! {
+            error!("{reason} Aborting service in 5s...");
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            std::process::abort()
+        }
+
         let handler_backoff = handler_backoff(ExponentialBuilder::default());
 
         let backoff_cfg = ExponentialBuilder::default()
@@ -112,117 +239,167 @@ impl<G: MessageGroup> Consumer<G> {
             .with_max_times(5);
         let mut backoff = backoff_cfg.build();
 
-        let abort = || async {
-            error!("Fatal error encountered in consumer loop! Aborting service in 5s...");
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            std::process::abort()
-        };
-
-        let abort_internal = || async {
-            error!("Consumer loop encountered too many errors! Aborting service in 5s...");
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            std::process::abort()
-        };
-
-        // 'reconnect:
-        loop {
+        'reconnect: loop {
             let mut stream = unsafe { self.to_stream() };
             let mut tasks = futures_util::stream::FuturesUnordered::new();
 
             'recv: loop {
                 enum Event<G> {
-                    Event(Option<Result<G, RecvError>>),
+                    Recv(Option<Result<(G, tracing::Span), RecvError>>),
                     Task(Result<(), tokio::task::JoinError>),
+                    Shutdown,
                 }
 
+                let recv =
+                    futures_util::future::poll_fn(|cx| Pin::new(&mut stream).poll_record(cx));
                 let evt = tokio::select! {
-                    s = stream.next() => Event::Event(s),
+                    () = self.shutdown.triggered() => Event::Shutdown,
+                    s = recv => Event::Recv(s),
                     Some(t) = tasks.next() => Event::Task(t),
                 };
 
                 match evt {
-                    Event::Event(Some(Ok(evt))) => {
+                    Event::Recv(Some(Ok((evt, span)))) => {
                         backoff = backoff_cfg.build();
                         let handle = handle.clone();
                         let mut backoff = handler_backoff.build();
 
-                        tasks.push(tokio::spawn(async move {
-                            'retry: loop {
-                                let fut = handle.clone()(evt.clone());
-
-                                match fut.await {
-                                    Ok(()) => break 'retry,
-                                    Err(e) => {
-                                        let severity = e.severity();
-                                        error!("{:?}", anyhow::Error::new(e));
-
-                                        match severity {
-                                            Severity::Transient => (),
-                                            Severity::Permanent => break 'retry,
-                                            Severity::Fatal => abort().await,
-                                        }
-                                    },
-                                }
+                        tasks.push(tokio::spawn(
+                            async move {
+                                'retry: loop {
+                                    let fut = handle.clone()(evt.clone());
+
+                                    match fut.await {
+                                        Ok(()) => break 'retry,
+                                        Err(e) => {
+                                            let severity = e.severity();
+                                            error!("{:?}", anyhow::Error::new(e));
+
+                                            match severity {
+                                                Severity::Transient => (),
+                                                Severity::Permanent => break 'retry,
+                                                Severity::Fatal => abort(FATAL).await,
+                                            }
+                                        },
+                                    }
 
-                                let Some(backoff) = backoff.next() else {
-                                    break 'retry;
-                                };
-                                tokio::time::sleep(backoff).await;
+                                    let Some(backoff) = backoff.next() else {
+                                        break 'retry;
+                                    };
+                                    tokio::time::sleep(backoff).await;
+                                }
                             }
-                        }));
+                            .instrument(span),
+                        ));
                     },
-                    Event::Event(Some(Err(e))) => {
+                    Event::Recv(Some(Err(e))) => {
                         warn!("Error receiving message: {e:?}");
                         let Some(backoff) = backoff.next() else {
-                            abort_internal().await
+                            abort("Consumer loop encountered too many errors!").await
                         };
                         tokio::time::sleep(backoff).await;
                     },
-                    Event::Event(None) => break 'recv,
+                    Event::Recv(None) => break 'recv,
                     Event::Task(Ok(())) => (),
                     Event::Task(Err(e)) => {
                         error!(
                             "{:?}",
                             anyhow::Error::new(e).context("Error joining consumer task")
                         );
-                        abort().await;
+                        abort(FATAL).await;
+                    },
+                    Event::Shutdown => {
+                        debug!(
+                            in_flight = tasks.len(),
+                            "Waiting for consumer tasks to finish"
+                        );
+
+                        while let Some(task) = tasks.next().await {
+                            if let Err(e) = task {
+                                error!(
+                                    "{:?}",
+                                    anyhow::Error::new(e).context("Error joining consumer task")
+                                );
+                            }
+                        }
+
+                        break 'reconnect;
                     },
                 }
             }
 
             warn!("Kafka message stream hung up");
             let Some(backoff) = backoff.next() else {
-                abort_internal().await
+                abort("Consumer loop encountered too many errors!").await
             };
             tokio::time::sleep(backoff).await;
         }
     }
 }
 
+pin_project_lite::pin_project! {
+    #[project = InnerProj]
+    enum Inner<'a> {
+        Kafka {
+            #[pin]
+            stream: rdkafka::consumer::MessageStream<'a>,
+        },
+        Memory {
+            stream: MemoryStream,
+        },
+    }
+}
+
 pin_project_lite::pin_project! {
     /// A stream of incoming messages for a consumer, parsed according to the
     /// type of the [`MessageGroup`] the consumer was constructed with
     pub struct ConsumerStream<'a, G> {
         #[pin]
-        stream: rdkafka::consumer::MessageStream<'a>,
+        stream: Inner<'a>,
         group: PhantomData<fn() -> G>,
     }
 }
 
+impl<'a, G: MessageGroup> ConsumerStream<'a, G> {
+    /// Poll for the next message, along with a span for handling it
+    fn poll_record(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<(G, tracing::Span), RecvError>>> {
+        fn decode<G: MessageGroup, M: Message>(msg: &M) -> Result<(G, tracing::Span), RecvError> {
+            let span = info_span!(
+                parent: None,
+                "consume_message",
+                topic = msg.topic(),
+                partition = msg.partition(),
+                offset = msg.offset(),
+            );
+            #[cfg(feature = "otlp")]
+            tracing_opentelemetry::OpenTelemetrySpanExt::set_parent(
+                &span,
+                crate::otel::extract(msg),
+            );
+
+            G::from_message(msg).map(|g| (g, span))
+        }
+
+        match self.project().stream.project() {
+            InnerProj::Kafka { stream } => stream
+                .poll_next(cx)
+                .map(|o| o.map(|r| r.map_err(RecvError::Kafka).and_then(|m| decode(&m)))),
+            InnerProj::Memory { stream } => {
+                stream.poll_next_unpin(cx).map(|o| o.map(|m| decode(&m)))
+            },
+        }
+    }
+}
+
 impl<'a, G: MessageGroup> Stream for ConsumerStream<'a, G> {
     type Item = Result<G, RecvError>;
 
     #[inline]
-    fn poll_next(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Self::Item>> {
-        self.project().stream.poll_next(cx).map(|o| {
-            o.map(|r| {
-                r.map_err(RecvError::Kafka)
-                    .and_then(|m| G::from_message(&m))
-            })
-        })
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_record(cx).map(|o| o.map(|r| r.map(|(g, _)| g)))
     }
 }
 
@@ -1,6 +1,10 @@
 //! A client for reading credit prices and submitting deduction events
 
-use std::{collections::HashMap, io::prelude::*, path::PathBuf};
+use std::{
+    collections::HashMap,
+    io::prelude::*,
+    path::{Path, PathBuf},
+};
 
 pub use hub_core_schemas::credits::Action;
 use hub_core_schemas::{credits, credits_mpsc};
@@ -9,7 +13,7 @@ use strum::IntoEnumIterator;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
-use crate::{prelude::*, producer, util::DebugShim};
+use crate::{bus::Bus, health, prelude::*, producer, shutdown::Shutdown};
 
 impl producer::Message for credits_mpsc::CreditsMpscEvent {
     type Key = credits::CreditsEventKey;
@@ -69,8 +73,11 @@ impl<I: LineItem> DeductionError<I> {
 /// sheet
 #[derive(Debug)]
 pub struct Config {
-    pub(crate) credit_sheet: PathBuf,
-    pub(crate) config: DebugShim<rdkafka::ClientConfig>,
+    pub(crate) credit_sheet: Option<PathBuf>,
+    pub(crate) identity: crate::Identity,
+    pub(crate) bus: Bus,
+    pub(crate) shutdown: Shutdown,
+    pub(crate) loaded: health::Flag,
 }
 
 impl Config {
@@ -83,6 +90,47 @@ impl Config {
     pub async fn build<I: LineItem>(self) -> Result<CreditsClient<I>> {
         CreditsClient::new(self).await
     }
+
+    /// Check that the credit sheet can be read and has an entry for every
+    /// line item, without connecting to Kafka
+    ///
+    /// This check always passes if credits are disabled for the command being
+    /// run.
+    ///
+    /// # Errors
+    /// This method returns an error if the credit sheet cannot be read or
+    /// parsed, or is missing an entry for any line item.
+    pub fn check<I: LineItem>(&self) -> Result<()> {
+        self.credit_sheet
+            .as_ref()
+            .map_or(Ok(()), |p| load_credit_sheet::<I>(p).map(|_| ()))
+    }
+}
+
+type RawCreditSheet = HashMap<String, HashMap<String, Option<u64>>>;
+
+/// Read and parse the credit sheet TOML file at the given path
+pub(crate) fn read_credit_sheet(path: &Path) -> Result<RawCreditSheet> {
+    let mut file = std::fs::File::open(path).context("Error opening credit sheet file")?;
+    let mut s = String::new();
+    file.read_to_string(&mut s)
+        .context("Error reading credit sheet file")?;
+
+    toml::from_str(&s).context("Syntax error in credit sheet")
+}
+
+/// Read the credit sheet at the given path, checking it has an entry for
+/// every line item
+fn load_credit_sheet<I: LineItem>(path: &Path) -> Result<RawCreditSheet> {
+    let toml = read_credit_sheet(path)?;
+
+    for item in I::iter() {
+        if !toml.contains_key(item.as_ref()) {
+            bail!("Missing entry in credit sheet for {item:?}");
+        }
+    }
+
+    Ok(toml)
 }
 
 /// A client for producing credit deduction line items
@@ -169,26 +217,22 @@ impl<I: LineItem> CreditsClient<I> {
     pub(crate) async fn new(config: Config) -> Result<Self> {
         let Config {
             credit_sheet,
-            config,
+            identity,
+            bus,
+            shutdown,
+            loaded,
         } = config;
-        let mut file =
-            std::fs::File::open(credit_sheet).context("Error opening credit sheet file")?;
-        let mut s = String::new();
-        file.read_to_string(&mut s)
-            .context("Error reading credit sheet file")?;
-        let toml: HashMap<String, HashMap<String, Option<u64>>> =
-            toml::from_str(&s).context("Syntax error in credit sheet")?;
-
-        for item in I::iter() {
-            if !toml.contains_key(item.as_ref()) {
-                bail!("Missing entry in credit sheet for {item:?}");
-            }
-        }
+        let Some(credit_sheet) = credit_sheet else {
+            bail!("Credits are not initialized for this command");
+        };
+        let toml = load_credit_sheet::<I>(&credit_sheet)?;
 
-        Ok(Self {
+        let client = Self {
             producer: producer::Config {
                 topic: "credits_mpsc".into(),
-                config,
+                identity,
+                bus,
+                shutdown,
             }
             .build()
             .await?,
@@ -207,7 +251,11 @@ impl<I: LineItem> CreditsClient<I> {
                 rng: Mutex::new(SeedableRng::from_entropy()),
             }
             .into(),
-        })
+        };
+
+        loaded.set(true);
+
+        Ok(client)
     }
 
     /// Borrow the underlying credit price sheet for this service's actions
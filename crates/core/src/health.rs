@@ -0,0 +1,157 @@
+//! Liveness and readiness reporting for services
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    PoisonError, RwLock,
+};
+
+use crate::prelude::*;
+
+/// Maximum time a single readiness check may take before it is considered
+/// failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A readiness probe for a single component of a service
+#[async_trait]
+pub trait Check: Send + Sync + 'static {
+    /// Determine whether the component is ready to serve traffic
+    ///
+    /// # Errors
+    /// This method should return an error describing why the component is not
+    /// ready.
+    async fn check(&self) -> Result<()>;
+}
+
+#[async_trait]
+impl<F: Fn() -> R + Send + Sync + 'static, R: Future<Output = Result<()>> + Send> Check for F {
+    #[inline]
+    async fn check(&self) -> Result<()> {
+        self().await
+    }
+}
+
+type Checks = Vec<(Cow<'static, str>, Arc<dyn Check>)>;
+
+/// A registry of readiness checks for this service
+#[derive(Clone, Default)]
+pub struct Health {
+    checks: Arc<RwLock<Checks>>,
+}
+
+impl fmt::Debug for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let checks = self.checks.read().unwrap_or_else(PoisonError::into_inner);
+
+        f.debug_struct("Health")
+            .field("checks", &checks.iter().map(|(n, _)| n).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Health {
+    /// Register a named readiness check
+    pub fn add_check(&self, name: impl Into<Cow<'static, str>>, check: impl Check) {
+        self.checks
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((name.into(), Arc::new(check)));
+    }
+
+    /// Register a named readiness flag, which reports the component as not
+    /// ready until it is set
+    #[must_use]
+    pub fn add_flag(&self, name: impl Into<Cow<'static, str>>) -> Flag {
+        let flag = Flag::default();
+        self.add_check(name, flag.clone());
+        flag
+    }
+
+    /// Run all registered readiness checks concurrently
+    pub async fn check(&self) -> Report {
+        let checks = self
+            .checks
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+
+        let results =
+            futures_util::future::join_all(checks.into_iter().map(|(name, check)| async move {
+                let res = match tokio::time::timeout(CHECK_TIMEOUT, check.check()).await {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(e)) => Err(format!("{e:#}")),
+                    Err(_) => Err("Timed out".into()),
+                };
+
+                (name, res)
+            }))
+            .await;
+
+        Report { results }
+    }
+}
+
+/// A readiness check that is toggled manually by the owning component
+#[derive(Debug, Clone, Default)]
+pub struct Flag(Arc<AtomicBool>);
+
+impl Flag {
+    /// Set whether the component owning this flag is ready
+    #[inline]
+    pub fn set(&self, ready: bool) {
+        self.0.store(ready, Ordering::Release);
+    }
+
+    /// Returns true if the component owning this flag is ready
+    #[inline]
+    #[must_use]
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+#[async_trait]
+impl Check for Flag {
+    async fn check(&self) -> Result<()> {
+        ensure!(self.is_set(), "Not ready");
+        Ok(())
+    }
+}
+
+/// The results of running all registered readiness checks
+#[derive(Debug, Default)]
+pub struct Report {
+    results: Vec<(Cow<'static, str>, Result<(), String>)>,
+}
+
+impl Report {
+    /// Append the result of a check run outside of a [`Health`] registry
+    pub(crate) fn push(&mut self, name: impl Into<Cow<'static, str>>, res: Result<()>) {
+        self.results
+            .push((name.into(), res.map_err(|e| format!("{e:#}"))));
+    }
+
+    /// Returns true if all readiness checks passed
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.results.iter().all(|(_, r)| r.is_ok())
+    }
+
+    /// Iterate over the name and result of each readiness check
+    pub fn results(&self) -> impl Iterator<Item = (&str, Result<(), &str>)> {
+        self.results
+            .iter()
+            .map(|(n, r)| (n.as_ref(), r.as_ref().copied().map_err(String::as_str)))
+    }
+
+    /// Render this report as a JSON object
+    #[must_use]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "ready": self.is_ready(),
+            "checks": self
+                .results()
+                .map(|(n, r)| (n.to_owned(), r.err().unwrap_or("ok").into()))
+                .collect::<serde_json::Map<_, _>>(),
+        })
+    }
+}
@@ -0,0 +1,350 @@
+//! Shared Kafka client configuration and authentication
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use backon::{BackoffBuilder, ExponentialBuilder};
+use rdkafka::{
+    admin::AdminClient, client::OAuthToken, config::FromClientConfigAndContext,
+    consumer::ConsumerContext, error::KafkaResult, types::RDKafka, ClientContext,
+};
+
+use crate::{prelude::*, util::DebugShim};
+
+/// How long a token read from an OAUTHBEARER token file is used before the
+/// file is read again
+const OAUTH_TOKEN_REFRESH: Duration = Duration::from_secs(60);
+
+/// SASL mechanism used to authenticate with the Kafka brokers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum SaslMechanism {
+    /// Plaintext username and password
+    #[value(name = "PLAIN")]
+    Plain,
+    /// Salted challenge-response with SHA-256
+    #[value(name = "SCRAM-SHA-256")]
+    ScramSha256,
+    /// Salted challenge-response with SHA-512
+    #[value(name = "SCRAM-SHA-512")]
+    ScramSha512,
+    /// Bearer tokens read from a file
+    #[value(name = "OAUTHBEARER")]
+    OAuthBearer,
+}
+
+impl SaslMechanism {
+    /// The librdkafka name of this mechanism
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Plain => "PLAIN",
+            Self::ScramSha256 => "SCRAM-SHA-256",
+            Self::ScramSha512 => "SCRAM-SHA-512",
+            Self::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+}
+
+/// Client context shared by all Kafka clients created by the runtime
+#[derive(Debug, Clone)]
+pub(crate) struct Context {
+    oauth_token_file: Option<Arc<Path>>,
+    principal: Arc<str>,
+    #[cfg(test)]
+    token_requests: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl Context {
+    /// Construct a context that supplies OAUTHBEARER tokens from the given
+    /// file, if any
+    pub fn new(oauth_token_file: Option<PathBuf>, principal: &str) -> Self {
+        Self {
+            oauth_token_file: oauth_token_file.map(Into::into),
+            principal: principal.into(),
+            #[cfg(test)]
+            token_requests: Arc::default(),
+        }
+    }
+}
+
+impl ClientContext for Context {
+    // This is fixed for the client type, so the callback is always
+    // registered, but librdkafka only invokes it for OAUTHBEARER, which
+    // cannot be configured without a token file
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    fn generate_oauth_token(
+        &self,
+        _oauthbearer_config: Option<&str>,
+    ) -> Result<OAuthToken, Box<dyn std::error::Error>> {
+        #[cfg(test)]
+        self.token_requests.fetch_add(1, Ordering::Relaxed);
+
+        let path = self
+            .oauth_token_file
+            .as_deref()
+            .ok_or("No OAUTHBEARER token file configured")?;
+        let token = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read OAUTHBEARER token file {path:?}"))?;
+        let expiry = SystemTime::now().duration_since(UNIX_EPOCH)? + OAUTH_TOKEN_REFRESH;
+
+        Ok(OAuthToken {
+            token: token.trim_end_matches(['\r', '\n']).into(),
+            principal_name: self.principal.to_string(),
+            lifetime_ms: expiry.as_millis().try_into()?,
+        })
+    }
+}
+
+impl ConsumerContext for Context {}
+
+/// A Kafka client configuration paired with the context to create clients
+/// with
+#[derive(Debug, Clone)]
+pub(crate) struct ClientConfig {
+    config: DebugShim<rdkafka::ClientConfig>,
+    context: Context,
+    pub boot_retry: BootRetry,
+}
+
+impl ClientConfig {
+    pub fn new(config: rdkafka::ClientConfig, context: Context, boot_retry: BootRetry) -> Self {
+        Self {
+            config: DebugShim(config),
+            context,
+            boot_retry,
+        }
+    }
+
+    /// Set a configuration property on this config
+    #[cfg(feature = "kafka")]
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.config.0.set(key, value);
+        self
+    }
+
+    /// Create a new client from this config
+    pub fn create<T: FromClientConfigAndContext<Context>>(&self) -> KafkaResult<T> {
+        self.config.0.create_with_context(self.context.clone())
+    }
+
+    /// Create a new admin client from this config
+    ///
+    /// librdkafka delivers OAUTHBEARER token refreshes on a client's main
+    /// queue, which rdkafka's admin client never polls, so when tokens are
+    /// read from a file the main queue is polled on a separate thread.
+    pub fn create_admin(&self) -> Result<Admin> {
+        let client: AdminClient<Context> = self.create()?;
+        let poller = self
+            .context
+            .oauth_token_file
+            .is_some()
+            .then(|| MainQueuePoller::start(client.inner().native_ptr()))
+            .transpose()?;
+
+        Ok(Admin { client, poller })
+    }
+
+    /// Wait until the brokers respond to a metadata request for each of the
+    /// given topics, retrying according to the boot retry policy
+    ///
+    /// Creating a client does not contact the brokers, so this is used to
+    /// hold off booting until they are reachable.
+    #[cfg(feature = "kafka")]
+    pub async fn await_brokers(&self, topics: &'static [&'static str]) -> Result<()> {
+        let admin = self
+            .create_admin()
+            .context("Failed to create Kafka admin client")?;
+        let admin = Arc::new(admin);
+
+        self.boot_retry
+            .run("Connecting to Kafka", |remaining| {
+                let admin = Arc::clone(&admin);
+
+                async move {
+                    tokio::task::spawn_blocking(move || {
+                        topics.iter().try_for_each(|topic| {
+                            admin
+                                .inner()
+                                .fetch_metadata(Some(topic), remaining)
+                                .map(|_| ())
+                        })
+                    })
+                    .await
+                    .context("Failed to join Kafka metadata request")?
+                    .context("Failed to fetch Kafka broker metadata")
+                }
+            })
+            .await
+    }
+}
+
+/// An admin client, along with the thread serving its main queue if needed
+pub(crate) struct Admin {
+    client: AdminClient<Context>,
+    poller: Option<MainQueuePoller>,
+}
+
+impl std::ops::Deref for Admin {
+    type Target = AdminClient<Context>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl Drop for Admin {
+    fn drop(&mut self) {
+        // The poller must stop before the client it polls is destroyed
+        if let Some(poller) = self.poller.take() {
+            poller.stop();
+        }
+    }
+}
+
+/// A thread polling the main queue of a librdkafka client to serve its
+/// callbacks
+struct MainQueuePoller {
+    stop: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+/// A librdkafka client handle that may be polled from another thread
+struct NativeClient(*mut RDKafka);
+
+// SAFETY: librdkafka client handles are thread-safe, and the poller is
+// stopped before the client is destroyed
+unsafe impl Send for NativeClient {}
+
+impl MainQueuePoller {
+    fn start(native: *mut RDKafka) -> Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let native = NativeClient(native);
+        let thread = std::thread::Builder::new()
+            .name("kafka-admin-poll".into())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || {
+                    let native = native;
+
+                    while !stop.load(Ordering::Relaxed) {
+                        // SAFETY: see NativeClient
+                        unsafe { rdkafka_sys::rd_kafka_poll(native.0, 100) };
+                    }
+                }
+            })
+            .context("Failed to spawn Kafka admin client polling thread")?;
+
+        Ok(Self { stop, thread })
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().ok();
+    }
+}
+
+/// Policy for retrying Kafka operations while the brokers may still be coming
+/// up during service boot
+#[derive(Debug, Clone)]
+pub(crate) struct BootRetry {
+    pub backoff: ExponentialBuilder,
+    pub timeout: Duration,
+}
+
+impl BootRetry {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            backoff: ExponentialBuilder::default()
+                .with_jitter()
+                .with_max_delay(Duration::from_secs(10))
+                .with_max_times(usize::MAX),
+            timeout,
+        }
+    }
+
+    /// Run the given operation until it succeeds, backing off between
+    /// attempts and giving up once the next attempt would start after the
+    /// timeout has elapsed
+    ///
+    /// The operation is passed the time remaining until the timeout, which it
+    /// should use to bound any requests it makes.  An attempt still running
+    /// when the timeout elapses is abandoned.
+    pub async fn run<T, F: FnMut(Duration) -> R, R: Future<Output = Result<T>>>(
+        &self,
+        what: &str,
+        mut f: F,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let mut backoff = self.backoff.build();
+        let mut attempt = 0_u32;
+
+        loop {
+            attempt += 1;
+            let remaining = self.timeout.saturating_sub(start.elapsed());
+            let err = match tokio::time::timeout(remaining, f(remaining)).await {
+                Ok(Ok(t)) => {
+                    if attempt > 1 {
+                        info!(attempt, elapsed = ?start.elapsed(), "{what} succeeded");
+                    }
+
+                    return Ok(t);
+                },
+                Ok(Err(e)) => e,
+                Err(_) => anyhow!("Attempt timed out after {remaining:?}"),
+            };
+
+            let elapsed = start.elapsed();
+            let Some(delay) = backoff.next().filter(|d| elapsed + *d < self.timeout) else {
+                return Err(err.context(format!("{what} failed after {attempt} attempt(s)")));
+            };
+
+            warn!(
+                attempt,
+                ?elapsed,
+                ?delay,
+                "{what} failed, retrying: {err:#}"
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oauth_token_refresh_admin_client() {
+        let token_file =
+            std::env::temp_dir().join(format!("hub-core-oauth-{}.token", std::process::id()));
+        std::fs::write(&token_file, "token\n").unwrap();
+
+        // Nothing listens on the discard port, so only the token refresh can
+        // make progress
+        let mut config = rdkafka::ClientConfig::new();
+        config
+            .set("bootstrap.servers", "127.0.0.1:9")
+            .set("security.protocol", "SASL_PLAINTEXT")
+            .set("sasl.mechanism", "OAUTHBEARER");
+        let context = Context::new(Some(token_file.clone()), "test");
+        let token_requests = Arc::clone(&context.token_requests);
+        let config = ClientConfig::new(config, context, BootRetry::new(Duration::from_secs(1)));
+
+        let admin = config.create_admin().unwrap();
+
+        let start = Instant::now();
+        while token_requests.load(Ordering::Relaxed) == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "Admin client never requested an OAUTHBEARER token"
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        drop(admin);
+        std::fs::remove_file(token_file).unwrap();
+    }
+}
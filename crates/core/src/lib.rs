@@ -26,12 +26,28 @@ pub extern crate serde_json;
 pub extern crate serde_with;
 pub extern crate thiserror;
 pub extern crate tokio;
+pub extern crate tokio_util;
 pub extern crate tracing;
 pub extern crate url;
 pub extern crate uuid;
 
 pub use runtime::*;
 
+/// Construct a [`StartConfig`] for the given service name, taking the service
+/// version from the calling crate's `CARGO_PKG_VERSION` and the Git commit from
+/// the `GIT_COMMIT` environment variable at build time, if set
+#[macro_export]
+macro_rules! start_config {
+    ($service_name:expr) => {
+        $crate::StartConfig {
+            service_name: $service_name,
+            version: ::std::env!("CARGO_PKG_VERSION"),
+            git_commit: ::std::option_env!("GIT_COMMIT"),
+            preflight: ::std::option::Option::None,
+        }
+    };
+}
+
 /// Common utilities for all crates
 pub mod prelude {
     pub use std::{
@@ -59,35 +75,77 @@ pub mod prelude {
     pub use tracing_subscriber::prelude::*;
     pub use url::Url;
 
-    pub use crate::triage::{Severity, Triage};
+    pub use crate::triage::{Severity, Triage, TriageExt};
 
     /// Result helper that defaults to [`anyhow::Error`]
     pub type Result<T, E = Error> = std::result::Result<T, E>;
 }
 
+mod admin;
 #[cfg(feature = "asset_proxy")]
 pub mod assets;
+#[cfg(feature = "kafka_internal")]
+mod bus;
 #[cfg(feature = "kafka")]
 pub mod consumer;
 #[cfg(feature = "credits")]
 pub mod credits;
+pub mod health;
+#[cfg(feature = "kafka_internal")]
+mod kafka;
+pub mod logging;
 #[cfg(feature = "metrics")]
 pub mod metrics;
+#[cfg(any(feature = "metrics", feature = "otlp"))]
+mod otel;
 #[cfg(feature = "kafka_internal")]
 pub mod producer;
 
+pub mod shutdown;
+pub mod supervisor;
 pub mod triage;
 pub mod util;
 
 mod runtime {
     use std::{
+        ffi::OsString,
         fmt,
+        net::SocketAddr,
+        panic::AssertUnwindSafe,
         path::{Path, PathBuf},
     };
 
-    use tracing_subscriber::{layer::Layered, EnvFilter};
+    use futures_util::future::BoxFuture;
+    use tracing_subscriber::{reload, EnvFilter};
+
+    use crate::{
+        health::{Health, Report},
+        logging::{LogFilter, LogFormat, RateLimit},
+        prelude::*,
+        shutdown::Shutdown,
+        supervisor::Supervisor,
+        util::DebugShim,
+    };
+
+    /// Upper bound on the time spent delivering buffered logs to Loki before
+    /// the process exits
+    const LOKI_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
-    use crate::{prelude::*, util::DebugShim};
+    /// Exit code used when the service entry point panics, matching the code
+    /// used by the Rust runtime for an uncaught panic
+    const PANIC_EXIT_CODE: i32 = 101;
+
+    /// Upper bound on the time spent delivering buffered spans to the OTLP
+    /// collector before the process exits
+    #[cfg(feature = "otlp")]
+    const OTLP_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Log filter used when none is given, more verbose in debug builds
+    const DEFAULT_LOG_FILTER: &str = if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "info"
+    };
 
     #[derive(Debug, clap::Args)]
     struct CommonArgs<T: clap::Args> {
@@ -95,30 +153,92 @@ mod runtime {
         #[arg(short, env)]
         jobs: Option<usize>,
 
-        /// The Kafka broker list
+        /// Maximum time in seconds to wait for running tasks to finish when
+        /// shutting down
+        #[arg(long, env, default_value_t = 30)]
+        shutdown_timeout: u64,
+
+        /// The environment profile selecting which `.env` files are loaded,
+        /// e.g. `staging` to load `.env.staging.local` and `.env.staging`
+        #[arg(long, env = "HUB_ENV")]
+        env_profile: Option<String>,
+
+        /// Address to serve liveness, readiness, metrics, and log filter
+        /// endpoints on
+        #[arg(long, env)]
+        admin_addr: Option<SocketAddr>,
+
+        /// The Kafka broker list, or `memory://` to exchange records through
+        /// an in-process bus for local development
+        ///
+        /// This is required unless the command being run disables Kafka.
         #[cfg(feature = "kafka_internal")]
         #[arg(long, env)]
-        kafka_brokers: String,
+        kafka_brokers: Option<String>,
+
+        /// SASL mechanism used to authenticate with Kafka, defaulting to
+        /// SCRAM-SHA-512 when a username is given
+        #[cfg(feature = "kafka_internal")]
+        #[arg(long, env, value_enum, ignore_case = true)]
+        kafka_sasl_mechanism: Option<super::kafka::SaslMechanism>,
 
         /// Kafka SASL username
         #[cfg(feature = "kafka_internal")]
         #[arg(long, env, requires("kafka_password"))]
         kafka_username: Option<String>,
 
-        /// Kafka SASL password
+        /// Kafka SASL password, which may instead be read from the file named
+        /// by `KAFKA_PASSWORD_FILE`
         #[cfg(feature = "kafka_internal")]
-        #[arg(long, env, requires("kafka_username"))]
+        #[arg(long, env, hide_env_values = true, requires("kafka_username"))]
         kafka_password: Option<DebugShim<String>>, // hide
 
+        /// Path to a file containing the bearer token for OAUTHBEARER
+        /// authentication, which is reread periodically
+        #[cfg(feature = "kafka_internal")]
+        #[arg(long, env)]
+        kafka_oauth_token_file: Option<PathBuf>,
+
         /// Whether to use SSL Kafka channels
         #[cfg(feature = "kafka_internal")]
         #[arg(long, env, default_value_t = true)]
         kafka_ssl: bool,
 
+        /// Maximum time in seconds to spend retrying Kafka connections while
+        /// booting before giving up, which must be nonzero
+        #[cfg(feature = "kafka_internal")]
+        #[arg(long, env, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        kafka_boot_timeout: u64,
+
+        /// Path to the client certificate for authenticating with Kafka over
+        /// mutual TLS
+        #[cfg(feature = "kafka_internal")]
+        #[arg(long, env, requires("kafka_ssl_key"))]
+        kafka_ssl_cert: Option<PathBuf>,
+
+        /// Path to the private key for the Kafka client certificate
+        #[cfg(feature = "kafka_internal")]
+        #[arg(long, env, requires("kafka_ssl_cert"))]
+        kafka_ssl_key: Option<PathBuf>,
+
+        /// Path to the CA certificate used to verify the Kafka brokers
+        #[cfg(feature = "kafka_internal")]
+        #[arg(long, env)]
+        kafka_ssl_ca: Option<PathBuf>,
+
+        /// Additional librdkafka configuration properties, overriding those
+        /// read from `KAFKA_CONFIG_*` variables (e.g. `KAFKA_CONFIG_LINGER_MS`
+        /// for `linger.ms`)
+        #[cfg(feature = "kafka_internal")]
+        #[arg(long = "kafka-config", value_name = "KEY=VALUE", value_parser = parse_kafka_config)]
+        kafka_config: Vec<(String, DebugShim<String>)>, // hide values
+
         /// Path to the credit price sheet TOML configuration file
+        ///
+        /// This is required unless the command being run disables credits.
         #[cfg(feature = "credits")]
         #[arg(long, env)]
-        credit_sheet: PathBuf,
+        credit_sheet: Option<PathBuf>,
 
         #[cfg(feature = "asset_proxy")]
         #[arg(long, env)]
@@ -128,13 +248,109 @@ mod runtime {
         extra: T,
     }
 
+    /// The optional subsystems initialized by the runtime before running the
+    /// service entry point or a [`ServiceCommand`]
+    ///
+    /// Each flag only has an effect if the crate feature providing the
+    /// subsystem is enabled.  Configurations for disabled subsystems are still
+    /// present on [`Common`], but fail to build.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Subsystems {
+        /// Connect to Kafka, requiring a broker list
+        pub kafka: bool,
+        /// Check and load the credit sheet, requiring its path
+        pub credits: bool,
+    }
+
+    impl Subsystems {
+        /// Initialize every subsystem
+        pub const ALL: Self = Self {
+            kafka: true,
+            credits: true,
+        };
+        /// Initialize no optional subsystems
+        pub const NONE: Self = Self {
+            kafka: false,
+            credits: false,
+        };
+    }
+
+    impl Default for Subsystems {
+        fn default() -> Self {
+            Self::ALL
+        }
+    }
+
+    /// A subcommand enum for a service binary, such as `migrate` or `seed`,
+    /// run by [`run_with_command`] in place of the service entry point
+    pub trait ServiceCommand: fmt::Debug + clap::Subcommand {
+        /// The subsystems to initialize before running this command
+        #[inline]
+        fn subsystems(&self) -> Subsystems {
+            Subsystems::ALL
+        }
+    }
+
+    /// The empty command enum used by [`run`]
+    #[derive(Debug)]
+    enum NoCommand {}
+
+    impl clap::FromArgMatches for NoCommand {
+        fn from_arg_matches(_: &clap::ArgMatches) -> Result<Self, clap::Error> {
+            Err(clap::Error::new(clap::error::ErrorKind::InvalidSubcommand))
+        }
+
+        fn update_from_arg_matches(&mut self, _: &clap::ArgMatches) -> Result<(), clap::Error> {
+            match *self {}
+        }
+    }
+
+    impl clap::Subcommand for NoCommand {
+        fn augment_subcommands(cmd: clap::Command) -> clap::Command {
+            cmd
+        }
+
+        fn augment_subcommands_for_update(cmd: clap::Command) -> clap::Command {
+            cmd
+        }
+
+        fn has_subcommand(_: &str) -> bool {
+            false
+        }
+    }
+
+    impl ServiceCommand for NoCommand {}
+
     /// Common data passed into the program entry point
     #[allow(missing_copy_implementations)]
     #[non_exhaustive]
     #[derive(Debug)]
     pub struct Common {
-        /// A Tokio runtime for use with async tasks
-        pub rt: tokio::runtime::Runtime,
+        /// A handle to the Tokio runtime for use with async tasks
+        pub rt: tokio::runtime::Handle,
+
+        /// A handle for requesting and observing graceful shutdown
+        pub shutdown: Shutdown,
+
+        /// Identifying information for this service process
+        pub identity: Identity,
+
+        /// A registry of readiness checks reported by the admin listener
+        pub health: Health,
+
+        /// A registry of named background tasks restarted on failure
+        pub supervisor: Supervisor,
+
+        /// A handle for changing the log filter at runtime
+        pub log_filter: LogFilter,
+
+        #[cfg(feature = "metrics")]
+        /// A meter provider whose metrics are served by the admin listener
+        pub meter_provider: super::metrics::MeterProvider,
+
+        #[cfg(feature = "metrics")]
+        /// The Prometheus registry backing [`meter_provider`](Self::meter_provider)
+        pub metrics_registry: super::metrics::Registry,
 
         #[cfg(feature = "kafka")]
         /// Configuration for creating a Kafka message producer for this service
@@ -153,24 +369,219 @@ mod runtime {
         pub asset_proxy: super::assets::AssetProxy,
     }
 
+    /// Resources owned by [`run`] that must outlive the service entry point
+    struct Lifecycle {
+        rt: tokio::runtime::Runtime,
+        shutdown: Shutdown,
+        preflight: Health,
+        flush: Arc<std::sync::Mutex<Option<Flush>>>,
+    }
+
+    type LokiTask = (
+        tracing_loki::BackgroundTaskController,
+        tokio::task::JoinHandle<()>,
+    );
+
+    /// Exporters holding buffered spans and logs that must be flushed before
+    /// the process exits
+    struct Flush {
+        loki: Option<LokiTask>,
+        #[cfg(feature = "otlp")]
+        otlp: bool,
+    }
+
+    impl Flush {
+        async fn run(self) {
+            let Self {
+                loki,
+                #[cfg(feature = "otlp")]
+                otlp,
+            } = self;
+
+            #[cfg(feature = "otlp")]
+            if otlp {
+                super::otel::shutdown(OTLP_FLUSH_TIMEOUT).await;
+            }
+
+            if let Some((ctl, task)) = loki {
+                ctl.shutdown().await;
+
+                if tokio::time::timeout(LOKI_FLUSH_TIMEOUT, task)
+                    .await
+                    .is_err()
+                {
+                    warn!("Timed out flushing logs to Loki");
+                }
+            }
+        }
+    }
+
+    impl Lifecycle {
+        /// Take ownership of the runtime and telemetry exporters, so that
+        /// buffered spans and logs can be flushed even if initializing the
+        /// rest of the service fails, returning the handle for the log filter
+        fn new(
+            rt: tokio::runtime::Runtime,
+            shutdown_timeout: Duration,
+            telemetry: Telemetry,
+        ) -> (Self, LogFilter) {
+            let Telemetry {
+                log_filter,
+                loki,
+                #[cfg(feature = "otlp")]
+                otlp,
+            } = telemetry;
+            let shutdown = Shutdown::new(shutdown_timeout);
+
+            let loki = loki.map(|(ctl, task)| {
+                let shutdown = shutdown.clone();
+                let task = rt.spawn(async move {
+                    task.await;
+
+                    if !shutdown.is_triggered() {
+                        error!("Loki exporter task quit unexpectedly!");
+                    }
+                });
+
+                (ctl, task)
+            });
+
+            let lifecycle = Self {
+                rt,
+                shutdown,
+                // Checks run in place of the entrypoint when booted with
+                // --check-config
+                preflight: Health::default(),
+                flush: Arc::new(std::sync::Mutex::new(Some(Flush {
+                    loki,
+                    #[cfg(feature = "otlp")]
+                    otlp,
+                }))),
+            };
+
+            (lifecycle, log_filter)
+        }
+
+        /// Flush buffered spans and logs, unless they have already been
+        /// flushed
+        async fn flush(flush: &std::sync::Mutex<Option<Flush>>) {
+            let flush = flush
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .take();
+
+            if let Some(flush) = flush {
+                flush.run().await;
+            }
+        }
+
+        /// Spawn a task that flushes buffered spans and logs and exits the
+        /// process once the drain deadline elapses, for entry points that do
+        /// not return when a shutdown is requested
+        fn spawn_watchdog(&self) -> tokio::task::JoinHandle<()> {
+            let shutdown = self.shutdown.clone();
+            let flush = Arc::clone(&self.flush);
+
+            self.rt.spawn(async move {
+                shutdown.expired().await;
+
+                error!(
+                    timeout = ?shutdown.timeout(),
+                    "Entrypoint did not return before the drain deadline, exiting now"
+                );
+                Self::flush(&flush).await;
+                std::process::exit(crate::triage::EXIT_UNKNOWN);
+            })
+        }
+
+        /// Request a shutdown, wait for registered tasks to drain, and flush any
+        /// buffered spans and logs
+        fn teardown(self) {
+            let Self {
+                rt,
+                shutdown,
+                preflight: _,
+                flush,
+            } = self;
+
+            rt.block_on(async move {
+                if !shutdown.drain().await {
+                    warn!(timeout = ?shutdown.timeout(), "Timed out waiting for tasks to finish");
+                }
+
+                Self::flush(&flush).await;
+            });
+
+            // Anything still running has already missed its deadline
+            rt.shutdown_background();
+        }
+
+        /// Install the process-wide signal handlers requesting a shutdown on
+        /// SIGINT or SIGTERM and reloading the log filter on SIGHUP
+        fn listen(&self, log_filter: &LogFilter, env_profile: Option<&str>) {
+            self.rt.spawn({
+                let shutdown = self.shutdown.clone();
+                async move {
+                    if let Err(e) = shutdown.listen().await {
+                        error!("{e:?}");
+                    }
+                }
+            });
+
+            self.rt.spawn({
+                let log_filter = log_filter.clone();
+                let env_files = env_files(env_profile);
+                async move {
+                    if let Err(e) = log_filter.reload_on_hangup(env_files).await {
+                        error!("{e:?}");
+                    }
+                }
+            });
+        }
+
+        /// Tear down the runtime and exit the process with the given code
+        fn exit(self, code: i32) -> ! {
+            self.teardown();
+            std::process::exit(code);
+        }
+    }
+
     impl Common {
-        #[instrument(name = "init_runtime", skip(loki_task))]
+        #[instrument(name = "init_runtime", skip(lifecycle, log_filter))]
         fn new<T: fmt::Debug + clap::Args>(
-            cfg: StartConfig,
+            identity: Identity,
+            lifecycle: &Lifecycle,
             args: CommonArgs<T>,
-            loki_task: Option<tracing_loki::BackgroundTask>,
+            subsystems: Subsystems,
+            log_filter: LogFilter,
         ) -> Result<(Self, T)> {
-            let StartConfig { service_name } = cfg;
             let CommonArgs {
-                jobs,
+                jobs: _,
+                shutdown_timeout: _,
+                env_profile: _,
+                admin_addr,
                 #[cfg(feature = "kafka_internal")]
                 kafka_brokers,
                 #[cfg(feature = "kafka_internal")]
+                kafka_sasl_mechanism,
+                #[cfg(feature = "kafka_internal")]
                 kafka_username,
                 #[cfg(feature = "kafka_internal")]
                 kafka_password,
                 #[cfg(feature = "kafka_internal")]
+                kafka_oauth_token_file,
+                #[cfg(feature = "kafka_internal")]
                 kafka_ssl,
+                #[cfg(feature = "kafka_internal")]
+                kafka_boot_timeout,
+                #[cfg(feature = "kafka_internal")]
+                kafka_ssl_cert,
+                #[cfg(feature = "kafka_internal")]
+                kafka_ssl_key,
+                #[cfg(feature = "kafka_internal")]
+                kafka_ssl_ca,
+                #[cfg(feature = "kafka_internal")]
+                kafka_config,
                 #[cfg(feature = "credits")]
                 credit_sheet,
                 #[cfg(feature = "asset_proxy")]
@@ -178,21 +589,30 @@ mod runtime {
                 extra,
             } = args;
 
-            let jobs = jobs.unwrap_or_else(num_cpus::get);
+            let Lifecycle {
+                rt,
+                shutdown,
+                preflight: _,
+                flush: _,
+            } = lifecycle;
 
-            let rt = tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .worker_threads(jobs)
-                .max_blocking_threads(jobs)
-                .build()
-                .context("Failed to construct Tokio runtime")?;
+            #[cfg(feature = "metrics")]
+            let (meter_provider, metrics_registry) = super::metrics::init(&identity)?;
+            #[cfg(feature = "metrics")]
+            super::metrics::register_runtime(&meter_provider, rt.handle())?;
 
-            if let Some(loki_task) = loki_task {
-                rt.spawn(async move {
-                    loki_task.await;
-                    error!("Loki exporter task quit unexpectedly!");
-                });
-            }
+            let health = Health::default();
+
+            health.add_check("shutdown", {
+                let shutdown = shutdown.clone();
+                move || {
+                    let triggered = shutdown.is_triggered();
+                    async move {
+                        ensure!(!triggered, "Service is shutting down");
+                        Ok(())
+                    }
+                }
+            });
 
             #[cfg(feature = "credits")]
             let credits_cfg;
@@ -201,50 +621,227 @@ mod runtime {
             #[cfg(feature = "kafka")]
             let consumer_cfg;
 
+            #[cfg(not(feature = "kafka_internal"))]
+            let _ = subsystems;
+
             #[cfg(feature = "kafka_internal")]
             {
                 use rdkafka::config::RDKafkaLogLevel;
                 use tracing::level_filters::LevelFilter;
 
-                let mut config = rdkafka::ClientConfig::new();
-                config
-                    .set("bootstrap.servers", kafka_brokers)
-                    .set_log_level(match LevelFilter::current() {
-                        LevelFilter::OFF => RDKafkaLogLevel::Critical,
-                        LevelFilter::ERROR => RDKafkaLogLevel::Error,
-                        LevelFilter::WARN => RDKafkaLogLevel::Notice,
-                        LevelFilter::INFO => RDKafkaLogLevel::Info,
-                        LevelFilter::DEBUG | LevelFilter::TRACE => RDKafkaLogLevel::Debug,
-                    });
-
-                if let Some((user, pass)) = kafka_username.zip(kafka_password) {
-                    config
-                        .set("sasl.mechanism", "SCRAM-SHA-512")
-                        .set("sasl.username", user)
-                        .set("sasl.password", pass.0)
-                        .set(
+                use super::kafka::SaslMechanism;
+
+                let kafka_brokers = if subsystems.kafka {
+                    Some(kafka_brokers.context(
+                        "A Kafka broker list must be given with --kafka-brokers or KAFKA_BROKERS",
+                    )?)
+                } else {
+                    None
+                };
+
+                let bus = match kafka_brokers.as_deref() {
+                    None => super::bus::Bus::Disabled,
+                    Some(super::bus::MEMORY_BROKERS) => {
+                        warn!("Using the in-memory event bus, records will not leave this process");
+                        super::bus::Bus::Memory(super::bus::MemoryBus::default())
+                    },
+                    Some(brokers) => {
+                        let mut config = rdkafka::ClientConfig::new();
+                        config
+                            .set("bootstrap.servers", brokers)
+                            .set(
+                                "client.id",
+                                format!(
+                                    "{}-{}-{}",
+                                    identity.service_name, identity.version, identity.instance_id
+                                ),
+                            )
+                            .set_log_level(match LevelFilter::current() {
+                                LevelFilter::OFF => RDKafkaLogLevel::Critical,
+                                LevelFilter::ERROR => RDKafkaLogLevel::Error,
+                                LevelFilter::WARN => RDKafkaLogLevel::Notice,
+                                LevelFilter::INFO => RDKafkaLogLevel::Info,
+                                LevelFilter::DEBUG | LevelFilter::TRACE => RDKafkaLogLevel::Debug,
+                            });
+
+                        let mechanism = kafka_sasl_mechanism.or_else(|| {
+                            kafka_username
+                                .is_some()
+                                .then_some(SaslMechanism::ScramSha512)
+                        });
+
+                        match mechanism {
+                            Some(SaslMechanism::OAuthBearer) => {
+                                ensure!(
+                                    kafka_oauth_token_file.is_some(),
+                                    "A Kafka OAuth token file is required for the OAUTHBEARER \
+                                     SASL mechanism"
+                                );
+                                ensure!(
+                                    kafka_username.is_none(),
+                                    "A Kafka username cannot be used with the OAUTHBEARER SASL \
+                                     mechanism"
+                                );
+                            },
+                            Some(m) => {
+                                let Some((user, pass)) = kafka_username.zip(kafka_password) else {
+                                    bail!(
+                                        "A Kafka username and password are required for the {} SASL \
+                                         mechanism",
+                                        m.as_str()
+                                    );
+                                };
+
+                                config
+                                    .set("sasl.username", user)
+                                    .set("sasl.password", pass.0);
+                            },
+                            None => (),
+                        }
+
+                        ensure!(
+                            kafka_oauth_token_file.is_none()
+                                || mechanism == Some(SaslMechanism::OAuthBearer),
+                            "A Kafka OAuth token file can only be used with the OAUTHBEARER SASL mechanism"
+                        );
+
+                        if let Some(mechanism) = mechanism {
+                            config.set("sasl.mechanism", mechanism.as_str());
+                        }
+
+                        config.set(
                             "security.protocol",
-                            if kafka_ssl {
-                                "SASL_SSL"
-                            } else {
-                                "SASL_PLAINTEXT"
+                            match (mechanism.is_some(), kafka_ssl) {
+                                (true, true) => "SASL_SSL",
+                                (true, false) => "SASL_PLAINTEXT",
+                                (false, true) => "SSL",
+                                (false, false) => "PLAINTEXT",
                             },
                         );
-                } else {
-                    config.set(
-                        "security.protocol",
-                        if kafka_ssl { "SSL" } else { "PLAINTEXT" },
-                    );
-                }
-                let config = config; // no more mut
+
+                        for (key, path) in [
+                            ("ssl.certificate.location", kafka_ssl_cert),
+                            ("ssl.key.location", kafka_ssl_key),
+                            ("ssl.ca.location", kafka_ssl_ca),
+                        ] {
+                            let Some(path) = path else { continue };
+
+                            ensure!(kafka_ssl, "Kafka SSL must be enabled to use {key}");
+                            ensure!(
+                                path.is_file(),
+                                "Kafka SSL file {path:?} for {key} does not exist"
+                            );
+
+                            config.set(key, path.to_string_lossy());
+                        }
+
+                        let kafka_config = kafka_config.into_iter().map(|(k, v)| (k, v.0));
+                        for (key, val) in kafka_config_env().chain(kafka_config) {
+                            config.set(key, val);
+                        }
+
+                        // The OAUTHBEARER token refresh callback is always
+                        // registered, so tokens must come from a token file
+                        ensure!(
+                            kafka_oauth_token_file.is_some()
+                                || !config
+                                    .get("sasl.mechanism")
+                                    .is_some_and(|m| m.eq_ignore_ascii_case("OAUTHBEARER")),
+                            "A Kafka OAuth token file is required for the OAUTHBEARER SASL \
+                             mechanism"
+                        );
+
+                        let config = super::kafka::ClientConfig::new(
+                            config,
+                            super::kafka::Context::new(
+                                kafka_oauth_token_file,
+                                identity.service_name,
+                            ),
+                            super::kafka::BootRetry::new(Duration::from_secs(kafka_boot_timeout)),
+                        );
+
+                        let probe = config
+                            .create_admin()
+                            .context("Failed to create Kafka health check client")?;
+                        let probe = Arc::new(probe);
+
+                        // Only request metadata for this service's own topic,
+                        // rather than for every topic in the cluster
+                        let topic = identity.service_name;
+                        let kafka_check = move |require_topic: bool| {
+                            let probe = Arc::clone(&probe);
+                            async move {
+                                let metadata = tokio::task::spawn_blocking(move || {
+                                    probe
+                                        .inner()
+                                        .fetch_metadata(Some(topic), Duration::from_secs(3))
+                                })
+                                .await
+                                .context("Failed to join Kafka metadata request")?
+                                .context("Failed to fetch Kafka broker metadata")?;
+
+                                // Metadata is still returned for a missing
+                                // topic, with the error set on the topic
+                                if require_topic {
+                                    let err = metadata
+                                        .topics()
+                                        .iter()
+                                        .find(|t| t.name() == topic)
+                                        .context("Kafka topic missing from broker metadata")?
+                                        .error();
+
+                                    if let Some(err) = err {
+                                        bail!(
+                                            "Kafka topic {topic:?} is unavailable: {}",
+                                            rdkafka::error::RDKafkaErrorCode::from(err)
+                                        );
+                                    }
+                                }
+
+                                Ok(())
+                            }
+                        };
+                        health.add_check("kafka", {
+                            let kafka_check = kafka_check.clone();
+                            move || kafka_check(false)
+                        });
+                        lifecycle
+                            .preflight
+                            .add_check("kafka", move || kafka_check(true));
+
+                        super::bus::Bus::Kafka(config)
+                    },
+                };
 
                 // Put MPSC producer init here
 
                 #[cfg(feature = "credits")]
                 {
+                    let credit_sheet = if subsystems.credits {
+                        Some(credit_sheet.context(
+                            "A credit sheet must be given with --credit-sheet or CREDIT_SHEET",
+                        )?)
+                    } else {
+                        None
+                    };
+
+                    if let Some(credit_sheet) = credit_sheet.clone() {
+                        lifecycle.preflight.add_check("credit_sheet", move || {
+                            let res = super::credits::read_credit_sheet(&credit_sheet).map(|_| ());
+                            async move { res }
+                        });
+                    }
+
                     credits_cfg = super::credits::Config {
+                        loaded: credit_sheet
+                            .as_ref()
+                            .map_or_else(crate::health::Flag::default, |_| {
+                                health.add_flag("credit_sheet")
+                            }),
                         credit_sheet,
-                        config: DebugShim(config.clone()),
+                        identity: identity.clone(),
+                        bus: bus.clone(),
+                        shutdown: shutdown.clone(),
                     };
                 }
 
@@ -253,16 +850,19 @@ mod runtime {
                 #[cfg(feature = "kafka")]
                 {
                     producer_cfg = super::producer::Config {
-                        topic: service_name.into(),
-                        config: DebugShim(config.clone()),
+                        topic: identity.service_name.into(),
+                        identity: identity.clone(),
+                        bus: bus.clone(),
+                        shutdown: shutdown.clone(),
                     };
                 }
 
                 #[cfg(feature = "kafka")]
                 {
                     consumer_cfg = super::consumer::Config {
-                        service_name: service_name.into(),
-                        config: DebugShim(config),
+                        service_name: identity.service_name.into(),
+                        bus,
+                        shutdown: shutdown.clone(),
                     };
                 }
             }
@@ -270,9 +870,40 @@ mod runtime {
             #[cfg(feature = "asset_proxy")]
             let asset_proxy = super::assets::AssetProxy::new(&asset_cdn)?;
 
+            if let Some(addr) = admin_addr {
+                let server = {
+                    let _guard = rt.enter();
+                    super::admin::bind(addr, super::admin::State {
+                        health: health.clone(),
+                        log_filter: log_filter.clone(),
+                        #[cfg(feature = "metrics")]
+                        registry: metrics_registry.clone(),
+                    })?
+                };
+
+                rt.spawn(async move {
+                    if let Err(e) = server.await {
+                        error!("{e:?}");
+                    }
+                });
+            }
+
             Ok((
                 Self {
-                    rt,
+                    rt: rt.handle().clone(),
+                    shutdown: shutdown.clone(),
+                    identity,
+                    supervisor: Supervisor::new(
+                        rt.handle().clone(),
+                        shutdown.clone(),
+                        health.clone(),
+                    ),
+                    health,
+                    log_filter,
+                    #[cfg(feature = "metrics")]
+                    meter_provider,
+                    #[cfg(feature = "metrics")]
+                    metrics_registry,
                     #[cfg(feature = "kafka")]
                     producer_cfg,
                     #[cfg(feature = "kafka")]
@@ -287,6 +918,165 @@ mod runtime {
         }
     }
 
+    fn build_runtime(jobs: Option<usize>) -> Result<tokio::runtime::Runtime> {
+        let jobs = jobs.unwrap_or_else(num_cpus::get);
+
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .worker_threads(jobs)
+            .max_blocking_threads(jobs)
+            .build()
+            .context("Failed to construct Tokio runtime")
+    }
+
+    /// The `.env` files to load for the given environment profile, in order of
+    /// decreasing precedence
+    ///
+    /// If no profile is given, `.env.dev` or `.env.prod` is used depending on
+    /// whether debug assertions are enabled.
+    fn env_files(profile: Option<&str>) -> Vec<PathBuf> {
+        match profile {
+            Some(p) => vec![
+                format!(".env.{p}.local").into(),
+                format!(".env.{p}").into(),
+                ".env".into(),
+            ],
+            None => [
+                ".env.local",
+                if cfg!(debug_assertions) {
+                    ".env.dev"
+                } else {
+                    ".env.prod"
+                },
+                ".env",
+            ]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect(),
+        }
+    }
+
+    /// Read the value of a command-line option or its environment variable
+    /// before arguments are parsed
+    fn early_arg(long: &str, env: &str) -> Option<String> {
+        let mut args = std::env::args_os().skip(1);
+        let prefix = format!("--{long}=");
+
+        while let Some(arg) = args.next() {
+            let arg_str = arg.to_string_lossy();
+
+            if arg_str == "--" {
+                break;
+            } else if arg_str.strip_prefix("--") == Some(long) {
+                return args.next().map(|a| a.to_string_lossy().into_owned());
+            } else if let Some(val) = arg_str.strip_prefix(&prefix) {
+                return Some(val.into());
+            }
+        }
+
+        std::env::var(env).ok().filter(|v| !v.is_empty())
+    }
+
+    /// Use the values in the given TOML file as defaults for the matching
+    /// arguments of a command, so they are overridden by both command-line
+    /// arguments and environment variables
+    ///
+    /// Keys may be given in either `snake_case` or `kebab-case`.
+    fn apply_config_file(mut cmd: clap::Command, path: &Path) -> Result<clap::Command> {
+        fn to_arg(val: toml::Value) -> Result<&'static str> {
+            let s = match val {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                v @ (toml::Value::Datetime(_) | toml::Value::Array(_) | toml::Value::Table(_)) => {
+                    bail!("Unsupported value {v}")
+                },
+            };
+
+            // Command defaults must be 'static, and are only set once at boot
+            Ok(Box::leak(s.into_boxed_str()))
+        }
+
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {path:?}"))?;
+        let table: toml::Table = toml::from_str(&text)
+            .with_context(|| format!("Syntax error in config file {path:?}"))?;
+
+        for (key, val) in table {
+            let id = key.replace('-', "_");
+
+            let Some(arg) = cmd.get_arguments().find(|a| a.get_id() == id.as_str()) else {
+                bail!("Unknown option {key:?} in config file {path:?}");
+            };
+
+            let vals = match val {
+                toml::Value::Array(a) => a.into_iter().map(to_arg).collect::<Result<Vec<_>>>(),
+                v => to_arg(v).map(|v| vec![v]),
+            }
+            .with_context(|| format!("Invalid value for {key:?} in config file {path:?}"))?;
+
+            // Clap panics on invalid defaults in debug builds, so report them
+            // as errors here instead
+            for val in &vals {
+                validate_default(arg, val).with_context(|| {
+                    format!("Invalid value {val:?} for {key:?} in config file {path:?}")
+                })?;
+            }
+
+            cmd = cmd.mut_arg(id, |a| a.default_values(vals));
+        }
+
+        Ok(cmd)
+    }
+
+    /// Check that the given value is accepted by the value parser of an
+    /// argument
+    fn validate_default(arg: &clap::Arg, val: &str) -> Result<()> {
+        // Only the value parser is copied, since any relations to other
+        // arguments would be dangling in a command of its own
+        let probe = clap::Arg::new("value")
+            .value_parser(arg.get_value_parser().clone())
+            .ignore_case(arg.is_ignore_case_set())
+            .allow_hyphen_values(true);
+
+        clap::Command::new("config")
+            .no_binary_name(true)
+            .arg(probe)
+            .try_get_matches_from([val])
+            .map(|_| ())
+            .map_err(|e| match std::error::Error::source(&e) {
+                Some(s) => anyhow!("{s}"),
+                None => anyhow!("{}", e.kind()),
+            })
+    }
+
+    /// Parse a `key=value` librdkafka configuration property, hiding the value
+    /// from debug output since it may be a secret such as `sasl.password`
+    #[cfg(feature = "kafka_internal")]
+    fn parse_kafka_config(s: &str) -> Result<(String, DebugShim<String>)> {
+        let (key, val) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected a property of the form KEY=VALUE"))?;
+        ensure!(!key.is_empty(), "Property name cannot be empty");
+
+        Ok((key.into(), DebugShim(val.into())))
+    }
+
+    /// Read librdkafka configuration properties from `KAFKA_CONFIG_*`
+    /// environment variables, mapping e.g. `KAFKA_CONFIG_SESSION_TIMEOUT_MS`
+    /// to `session.timeout.ms`
+    #[cfg(feature = "kafka_internal")]
+    fn kafka_config_env() -> impl Iterator<Item = (String, String)> {
+        std::env::vars().filter_map(|(var, val)| {
+            let key = var
+                .strip_prefix("KAFKA_CONFIG_")
+                .filter(|k| !k.is_empty())?;
+
+            Some((key.to_lowercase().replace('_', "."), val))
+        })
+    }
+
     fn dotenv(name: impl AsRef<Path>) -> Result<Option<PathBuf>, dotenv::Error> {
         match dotenv::from_filename(name) {
             Ok(p) => Ok(Some(p)),
@@ -295,164 +1085,939 @@ mod runtime {
         }
     }
 
+    /// For each argument with hidden environment values, populate its
+    /// environment variable from the file named by the same variable suffixed
+    /// with `_FILE`, if one is given
+    ///
+    /// This allows secrets to be mounted as files rather than passed directly
+    /// in the environment.
+    fn load_secret_files(cmd: &clap::Command) -> Result<()> {
+        for arg in cmd.get_arguments().filter(|a| a.is_hide_env_values_set()) {
+            let Some(var) = arg.get_env().and_then(|v| v.to_str()) else {
+                continue;
+            };
+            let file_var = format!("{var}_FILE");
+            let Some(path) = std::env::var_os(&file_var) else {
+                continue;
+            };
+
+            ensure!(
+                std::env::var_os(var).is_none(),
+                "Only one of {var} and {file_var} may be set"
+            );
+
+            let secret = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {file_var} file {path:?}"))?;
+            std::env::set_var(var, secret.trim_end_matches(['\r', '\n']));
+        }
+
+        Ok(())
+    }
+
     #[derive(Debug, clap::Parser)]
-    struct Opts<T: fmt::Debug + clap::Args> {
+    struct Opts<T: fmt::Debug + clap::Args, C: ServiceCommand> {
+        /// Path to a TOML file providing default values for any other option,
+        /// which are overridden by both arguments and environment variables
+        #[arg(long = "config", env = "HUB_CONFIG")]
+        config_file: Option<PathBuf>,
+
         /// The log filter, using env_logger-like syntax
         #[arg(long, env = "RUST_LOG")]
         log_filter: Option<String>,
 
+        /// The format to write console logs in
+        #[arg(long, env, value_enum, default_value_t)]
+        log_format: LogFormat,
+
+        /// Maximum number of times an event with the same callsite and message
+        /// is logged per rate limit window, with a summary of any suppressed
+        /// events logged at the end of the window.  Reported panics are never
+        /// suppressed.
+        #[arg(long, env)]
+        log_rate_limit: Option<u32>,
+
+        /// Length of the log rate limit window, in seconds
+        #[arg(long, env, default_value_t = 60)]
+        log_rate_limit_window: u64,
+
         /// Endpoint to use for exporting logs to Grafana Loki
         #[arg(long, env)]
         loki_endpoint: Option<Url>,
 
+        /// gRPC endpoint to use for exporting traces to an OpenTelemetry
+        /// collector
+        #[cfg(feature = "otlp")]
+        #[arg(long, env)]
+        otlp_endpoint: Option<Url>,
+
+        /// Serve Tokio task diagnostics to `tokio-console`, configured by the
+        /// `TOKIO_CONSOLE_*` environment variables
+        #[cfg(feature = "console")]
+        #[arg(long, env)]
+        tokio_console: bool,
+
+        /// Validate the configuration and connectivity of this service, print
+        /// a report, and exit without running the service
+        #[arg(long)]
+        check_config: bool,
+
         #[command(flatten)]
         common: CommonArgs<T>,
+
+        #[command(subcommand)]
+        command: Option<C>,
     }
 
     macro_rules! init_error {
         ($($args:tt)*) => ({
             ::tracing::error!($($args)*);
-            ::std::process::exit(1);
+            ::std::process::exit($crate::triage::EXIT_UNKNOWN);
         })
     }
 
-    fn fmt_layer<S>() -> tracing_subscriber::fmt::Layer<S> {
-        tracing_subscriber::fmt::layer()
+    /// Endpoints to export telemetry to, if any
+    struct Exporters {
+        loki_endpoint: Option<Url>,
+        #[cfg(feature = "otlp")]
+        otlp_endpoint: Option<Url>,
+        #[cfg(feature = "console")]
+        tokio_console: bool,
+    }
+
+    /// Background state for the telemetry exporters installed by
+    /// [`init_subscriber`]
+    struct Telemetry {
+        log_filter: LogFilter,
+        loki: Option<(
+            tracing_loki::BackgroundTaskController,
+            tracing_loki::BackgroundTask,
+        )>,
+        #[cfg(feature = "otlp")]
+        otlp: bool,
+    }
+
+    /// Load the `.env` files, secret files, and config file, then parse the
+    /// command line, exiting the process on failure
+    fn parse_opts<T: fmt::Debug + clap::Args, C: ServiceCommand>() -> Opts<T, C> {
+        let profile = early_arg("env-profile", "HUB_ENV");
+
+        env_files(profile.as_deref())
+            .into_iter()
+            .try_for_each(|p| -> Result<()> {
+                if dotenv(&p)
+                    .with_context(|| format!("Failed to load .env file {p:?}"))?
+                    .is_some()
+                {
+                    info!(path = ?p, ?profile, "Loaded .env file");
+                }
+
+                Ok(())
+            })
+            .unwrap_or_else(|e| init_error!("Failed to load .env files: {e:?}"));
+
+        let mut cmd = <Opts<T, C> as clap::CommandFactory>::command();
+
+        load_secret_files(&cmd)
+            .unwrap_or_else(|e| init_error!("Failed to load secret files: {e:?}"));
+
+        if let Some(path) = early_arg("config", "HUB_CONFIG") {
+            cmd = apply_config_file(cmd, path.as_ref())
+                .unwrap_or_else(|e| init_error!("Failed to load config file: {e:?}"));
+            info!(?path, "Loaded config file");
+        }
+
+        <Opts<T, C> as clap::FromArgMatches>::from_arg_matches(&cmd.get_matches())
+            .unwrap_or_else(|e| e.exit())
     }
 
-    #[instrument(name = "bootstrap_logger", skip(log_filter, f))]
-    fn init_subscriber<
-        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
-    >(
+    #[instrument(name = "bootstrap_logger", skip_all)]
+    fn init_subscriber(
+        rt: &tokio::runtime::Runtime,
+        identity: &Identity,
         log_filter: impl AsRef<str>,
-        f: impl FnOnce(Layered<EnvFilter, tracing_subscriber::Registry>) -> S,
-    ) where
-        Layered<tracing_subscriber::fmt::Layer<S>, S>: Into<tracing::Dispatch>,
-    {
+        log_format: LogFormat,
+        rate_limit: Option<RateLimit>,
+        exporters: Exporters,
+    ) -> Telemetry {
+        let Exporters {
+            loki_endpoint,
+            #[cfg(feature = "otlp")]
+            otlp_endpoint,
+            #[cfg(feature = "console")]
+            tokio_console,
+        } = exporters;
+
+        let (loki_layer, loki) = loki_endpoint
+            .map(|e| {
+                let url = e.join("/").unwrap_or_else(|e| {
+                    init_error!("Invalid Loki endpoint: {e}");
+                });
+
+                let mut builder = tracing_loki::builder()
+                    .label("host_name", &identity.host_name)?
+                    .label("service_name", identity.service_name)?
+                    .label("service_version", identity.version)?
+                    .label("instance_id", identity.instance_id.to_string())?;
+
+                if let Some(commit) = identity.git_commit {
+                    builder = builder.label("git_commit", commit)?;
+                }
+
+                builder
+                    .build_controller_url(url)
+                    .map(|(layer, ctl, task)| (layer, (ctl, task)))
+            })
+            .transpose()
+            .unwrap_or_else(|e| init_error!("Failed to initialize Loki exporter: {e}"))
+            .unzip();
+
         let log_filter = log_filter.as_ref();
-        let reg = tracing_subscriber::Registry::default().with(
-            EnvFilter::try_new(log_filter).unwrap_or_else(|e| {
+        let (filter, handle) =
+            reload::Layer::new(EnvFilter::try_new(log_filter).unwrap_or_else(|e| {
                 init_error!("Invalid log filter {log_filter:?}: {e}");
-            }),
-        );
+            }));
+
+        // The log filter only applies to the exporters, so that Tokio's
+        // instrumentation always reaches the console layer if enabled
+        let mut layers: Vec<
+            Box<dyn tracing_subscriber::Layer<tracing_subscriber::Registry> + Send + Sync>,
+        > = vec![super::logging::layer(log_format, identity.service_name)];
+        layers.extend(loki_layer.map(tracing_subscriber::Layer::boxed));
+
+        #[cfg(feature = "otlp")]
+        let tracer = otlp_endpoint.map(|e| {
+            // The batch span processor is spawned onto the service runtime
+            let _guard = rt.enter();
+
+            super::otel::tracer(&e, super::otel::resource(identity))
+                .unwrap_or_else(|e| init_error!("Failed to initialize OTLP exporter: {e:?}"))
+        });
+        #[cfg(feature = "otlp")]
+        let otlp = tracer.is_some();
+        #[cfg(feature = "otlp")]
+        layers.extend(tracer.map(|t| tracing_opentelemetry::layer().with_tracer(t).boxed()));
+
+        if let Some(rate_limit) = rate_limit.clone() {
+            rt.spawn(async move {
+                let mut interval = tokio::time::interval(rate_limit.window());
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+                    rate_limit.flush();
+                }
+            });
+        }
+
+        let reg = tracing_subscriber::Registry::default()
+            .with(layers.with_filter(super::logging::with_rate_limit(filter, rate_limit)));
+
+        #[cfg(feature = "console")]
+        let reg = reg.with(tokio_console.then(|| {
+            console_subscriber::ConsoleLayer::builder()
+                .with_default_env()
+                .spawn()
+        }));
 
-        f(reg)
-            .with(fmt_layer())
-            .try_init()
+        reg.try_init()
             .unwrap_or_else(|e| init_error!("Failed to set tracing subscriber: {e}"));
+
+        Telemetry {
+            log_filter: LogFilter::new(handle, log_filter),
+            loki,
+            #[cfg(feature = "otlp")]
+            otlp,
+        }
+    }
+
+    /// Install [`panic_hook`], keeping the default hook so panics still reach
+    /// stderr if the log filter discards the reported event
+    fn install_panic_hook() {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            panic_hook(info);
+            default_hook(info);
+        }));
     }
 
+    /// Report a panic through the active tracing subscriber so it reaches
+    /// every configured exporter, in addition to the default panic output
+    ///
+    /// A backtrace is only captured if enabled by `RUST_BACKTRACE` or
+    /// `RUST_LIB_BACKTRACE`.
+    fn panic_hook(info: &std::panic::PanicInfo) {
+        let payload = info.payload();
+        let msg = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let location = info
+            .location()
+            .map_or_else(|| "<unknown>".into(), ToString::to_string);
+        let thread = std::thread::current();
+        let backtrace = std::backtrace::Backtrace::capture();
+        let backtrace = (backtrace.status() == std::backtrace::BacktraceStatus::Captured)
+            .then(|| tracing::field::display(backtrace));
+
+        error!(
+            target: super::logging::PANIC_TARGET,
+            thread = thread.name().unwrap_or("<unnamed>"),
+            %location,
+            backtrace,
+            "Panicked: {msg}"
+        );
+    }
+
+    /// A service-specific check run by [`StartConfig::preflight`]
+    pub type Preflight = for<'a> fn(&'a Common) -> BoxFuture<'a, Result<()>>;
+
     /// Initial parameters for booting a service
+    ///
+    /// The [`start_config`](crate::start_config) macro fills in the version
+    /// and commit from the calling crate's build environment.
     #[derive(Debug)]
     #[allow(missing_copy_implementations)]
     pub struct StartConfig {
         /// The name of this service, used as an identifier in logs and event
         /// buses
         pub service_name: &'static str,
+
+        /// The version of this service, usually its crate version
+        pub version: &'static str,
+
+        /// The Git commit this service was built from, if known
+        pub git_commit: Option<&'static str>,
+
+        /// Service-specific configuration checks to run when booted with
+        /// `--check-config`, such as validating the credit sheet against the
+        /// service's line items
+        ///
+        /// The returned future is run to completion on the service runtime,
+        /// so checks may await other asynchronous checks such as
+        /// [`consumer::Config::check`](crate::consumer::Config::check):
+        ///
+        /// ```
+        /// use holaplex_hub_core::{futures_util::future::BoxFuture, prelude::*, Common};
+        ///
+        /// fn preflight(common: &Common) -> BoxFuture<'_, Result<()>> {
+        ///     async move {
+        ///         ensure!(
+        ///             common.health.check().await.is_ready(),
+        ///             "Service is unhealthy"
+        ///         );
+        ///         Ok(())
+        ///     }
+        ///     .boxed()
+        /// }
+        ///
+        /// let cfg = holaplex_hub_core::StartConfig {
+        ///     preflight: Some(preflight),
+        ///     ..holaplex_hub_core::start_config!("my-service")
+        /// };
+        /// # drop(cfg);
+        /// ```
+        pub preflight: Option<Preflight>,
+    }
+
+    /// Identifying information for a running service process, attached to its
+    /// logs, traces, metrics, and Kafka clients
+    #[derive(Debug, Clone)]
+    #[non_exhaustive]
+    pub struct Identity {
+        /// The name of this service
+        pub service_name: &'static str,
+
+        /// The version of this service
+        pub version: &'static str,
+
+        /// The Git commit this service was built from, if known
+        pub git_commit: Option<&'static str>,
+
+        /// The hostname of the machine running this process
+        pub host_name: String,
+
+        /// A random ID unique to this process
+        pub instance_id: uuid::Uuid,
+    }
+
+    impl Identity {
+        fn new(cfg: &StartConfig) -> Result<Self> {
+            let StartConfig {
+                service_name,
+                version,
+                git_commit,
+                preflight: _,
+            } = *cfg;
+
+            Ok(Self {
+                service_name,
+                version,
+                git_commit,
+                host_name: hostname::get()
+                    .context("Failed to get system hostname")?
+                    .to_string_lossy()
+                    .into_owned(),
+                instance_id: instance_id(),
+            })
+        }
+    }
+
+    /// Generate a random ID for this process
+    fn instance_id() -> uuid::Uuid {
+        use std::{
+            collections::hash_map::RandomState,
+            hash::{BuildHasher, Hasher},
+            time::{SystemTime, UNIX_EPOCH},
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        // RandomState is seeded from the OS random number generator
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+
+        #[allow(clippy::cast_possible_truncation)]
+        uuid::Uuid::from_u64_pair(now.as_nanos() as u64, hasher.finish())
     }
 
     /// Perform environment setup and run the requested entrypoint
+    ///
+    /// Once the entrypoint returns or panics a graceful shutdown is requested,
+    /// and tasks registered with [`Common::shutdown`] are given until the
+    /// configured deadline to finish before buffered logs are flushed and the
+    /// process exits.  If a shutdown is requested while the entrypoint is
+    /// running and it has not returned by the deadline, buffered logs are
+    /// flushed and the process exits with
+    /// [`EXIT_UNKNOWN`](crate::triage::EXIT_UNKNOWN).
+    ///
+    /// Panics on any thread are logged with their location, and with a
+    /// backtrace if enabled by `RUST_BACKTRACE`, before being reported by the
+    /// default panic hook.
+    ///
+    /// If the entrypoint or runtime initialization fails with an error whose
+    /// severity is recognized by [`find_severity`](crate::triage::find_severity),
+    /// the process exits with the code for its severity given by
+    /// [`Severity::exit_code`], or with
+    /// [`EXIT_UNKNOWN`](crate::triage::EXIT_UNKNOWN) otherwise.  Errors
+    /// defined by the service should be wrapped with
+    /// [`TriageExt::triaged`](crate::triage::TriageExt::triaged) to be
+    /// recognized.  A panic exits with code 101.
     pub fn run<T: fmt::Debug + clap::Args>(
         cfg: StartConfig,
         main: impl FnOnce(Common, T) -> Result<()>,
     ) {
-        let StartConfig { service_name } = cfg;
+        run_with_command(cfg, |common, extra, _: Option<NoCommand>| {
+            main(common, extra)
+        });
+    }
 
-        // Construct a temporary logger on this thread until the full logger is ready
-        let smuggled = tracing::subscriber::with_default(
-            tracing_subscriber::Registry::default().with(fmt_layer()),
+    /// Perform environment setup and run either the requested subcommand or,
+    /// if none was given, the service entrypoint
+    ///
+    /// The entrypoint is passed the parsed subcommand, if any.  Only the
+    /// [`Subsystems`] requested by the subcommand are initialized, and all of
+    /// them are initialized when no subcommand is given.  Otherwise this
+    /// behaves like [`run`].
+    // StartConfig is taken by value to match run and the start_config macro
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn run_with_command<T: fmt::Debug + clap::Args, C: ServiceCommand>(
+        cfg: StartConfig,
+        main: impl FnOnce(Common, T, Option<C>) -> Result<()>,
+    ) {
+        install_panic_hook();
+
+        // Construct a temporary logger on this thread until the full logger is
+        // ready, using the environment for its format until arguments are parsed
+        let opts: Opts<T, C> = tracing::subscriber::with_default(
+            tracing_subscriber::Registry::default().with(super::logging::layer(
+                LogFormat::from_env(),
+                cfg.service_name,
+            )),
             || {
-                let span = error_span!("boot").entered();
+                let _span = error_span!("boot").entered();
+                parse_opts()
+            },
+        );
 
-                [
-                    ".env.local",
-                    if cfg!(debug_assertions) {
-                        ".env.dev"
-                    } else {
-                        ".env.prod"
-                    },
-                    ".env",
-                ]
-                .into_iter()
-                .try_for_each(|p| {
-                    dotenv(p)
-                        .map(|_| ())
-                        .with_context(|| format!("Failed to load .env file {p:?}"))
-                })
-                .unwrap_or_else(|e| init_error!("Failed to load .env files: {e:?}"));
-
-                let opts = clap::Parser::parse();
-                drop(span);
+        let smuggled = tracing::subscriber::with_default(
+            tracing_subscriber::Registry::default()
+                .with(super::logging::layer(opts.log_format, cfg.service_name)),
+            || {
                 let span = error_span!("boot", ?opts).entered();
                 let Opts {
+                    config_file: _,
                     log_filter,
+                    log_format,
+                    log_rate_limit,
+                    log_rate_limit_window,
                     loki_endpoint,
+                    #[cfg(feature = "otlp")]
+                    otlp_endpoint,
+                    #[cfg(feature = "console")]
+                    tokio_console,
+                    check_config,
                     common,
+                    command,
                 } = opts;
 
-                let log_filter = log_filter.map_or_else(
-                    || {
-                        Borrowed(if cfg!(debug_assertions) {
-                            "debug"
-                        } else {
-                            "info"
-                        })
+                let log_filter = log_filter.map_or(Borrowed(DEFAULT_LOG_FILTER), Owned);
+                let identity = Identity::new(&cfg)
+                    .unwrap_or_else(|e| init_error!("Failed to identify service: {e:?}"));
+
+                let rt = build_runtime(common.jobs)
+                    .unwrap_or_else(|e| init_error!("Failed to initialize runtime: {e:?}"));
+
+                let telemetry = init_subscriber(
+                    &rt,
+                    &identity,
+                    log_filter,
+                    log_format,
+                    log_rate_limit
+                        .map(|n| RateLimit::new(n, Duration::from_secs(log_rate_limit_window))),
+                    Exporters {
+                        loki_endpoint,
+                        #[cfg(feature = "otlp")]
+                        otlp_endpoint,
+                        #[cfg(feature = "console")]
+                        tokio_console,
                     },
-                    Owned,
                 );
 
-                let hostname = hostname::get()
-                    .unwrap_or_else(|e| init_error!("Failed to get system hostname: {e}"))
-                    .to_string_lossy()
-                    .into_owned();
-
-                let (loki_layer, loki_task) = loki_endpoint
-                    .map(|e| {
-                        tracing_loki::layer(
-                            e,
-                            [
-                                ("host_name".into(), hostname),
-                                ("service_name".into(), service_name.into()),
-                            ]
-                            .into_iter()
-                            .collect(),
-                            [].into_iter().collect(),
-                        )
-                    })
-                    .transpose()
-                    .unwrap_or_else(|e| init_error!("Failed to initialize Loki exporter: {e}"))
-                    .unzip();
-
-                if let Some(loki_layer) = loki_layer {
-                    init_subscriber(log_filter, |r| r.with(loki_layer));
-                } else {
-                    init_subscriber(log_filter, |r| r);
-                }
-
                 drop(span);
 
-                (common, loki_task)
+                (rt, identity, check_config, common, command, telemetry)
             },
         );
 
-        let (common, loki_task) = smuggled;
+        let (rt, identity, check_config, common, command, telemetry) = smuggled;
+        let subsystems = command
+            .as_ref()
+            .map_or(Subsystems::ALL, ServiceCommand::subsystems);
 
         error_span!("run").in_scope(|| {
-            let (common, extra) = match Common::new(cfg, common, loki_task) {
-                Ok(t) => t,
-                Err(e) => {
-                    error!("Failed to initialize runtime: {e:?}");
-                    std::process::exit(-1);
+            let (lifecycle, log_filter) =
+                Lifecycle::new(rt, Duration::from_secs(common.shutdown_timeout), telemetry);
+            lifecycle.listen(&log_filter, common.env_profile.as_deref());
+
+            // Tear down on failure as well, so the error reaches the exporters
+            let (common, extra) =
+                match Common::new(identity, &lifecycle, common, subsystems, log_filter) {
+                    Ok(t) => t,
+                    Err(e) if check_config => {
+                        let mut report = Report::default();
+                        report.push("runtime", Err(e));
+                        lifecycle.exit(print_check_report(&report));
+                    },
+                    Err(e) => {
+                        error!("Failed to initialize runtime: {e:?}");
+                        lifecycle.exit(exit_code(&e));
+                    },
+                };
+
+            if check_config {
+                let mut report = lifecycle.rt.block_on(lifecycle.preflight.check());
+                if let Some(preflight) = cfg.preflight {
+                    report.push("service", lifecycle.rt.block_on(preflight(&common)));
+                }
+
+                lifecycle.exit(print_check_report(&report));
+            }
+
+            let watchdog = lifecycle.spawn_watchdog();
+            let code =
+                match std::panic::catch_unwind(AssertUnwindSafe(|| main(common, extra, command))) {
+                    Ok(Ok(())) => 0,
+                    Ok(Err(e)) => {
+                        error!("{e:?}");
+                        exit_code(&e)
+                    },
+                    // Already reported by the panic hook
+                    Err(_) => PANIC_EXIT_CODE,
+                };
+
+            watchdog.abort();
+            lifecycle.exit(code);
+        });
+    }
+
+    /// Get the exit code for a service that failed with the given error
+    fn exit_code(err: &Error) -> i32 {
+        let severity = crate::triage::find_severity(err);
+        debug!(?severity, "Triaged exit error");
+
+        severity.map_or(crate::triage::EXIT_UNKNOWN, Severity::exit_code)
+    }
+
+    /// Log any failed checks from a `--check-config` run and print the report
+    /// as JSON to stdout, returning the exit code for the process
+    fn print_check_report(report: &Report) -> i32 {
+        for (check, res) in report.results() {
+            if let Err(e) = res {
+                error!(check, "Configuration check failed: {e}");
+            }
+        }
+
+        println!("{}", report.to_json());
+
+        i32::from(!report.is_ready())
+    }
+
+    /// Perform environment setup and run the requested async entrypoint on the
+    /// service runtime
+    ///
+    /// This behaves like [`run`], except that once a shutdown is requested the
+    /// entrypoint is given until the configured deadline to finish, after
+    /// which it is abandoned and the process exits with an error.  The
+    /// entrypoint and registered tasks share the same deadline, measured from
+    /// when the shutdown was first requested.
+    pub fn run_async<T: fmt::Debug + clap::Args, F: Future<Output = Result<()>>>(
+        cfg: StartConfig,
+        main: impl FnOnce(Common, T) -> F,
+    ) {
+        run(cfg, |common, extra| {
+            let rt = common.rt.clone();
+            let shutdown = common.shutdown.clone();
+
+            rt.block_on(async move {
+                let main = main(common, extra);
+                tokio::pin!(main);
+
+                tokio::select! {
+                    res = &mut main => res,
+                    () = shutdown.expired() => {
+                        Err(anyhow!("Timed out waiting for entrypoint to finish"))
+                    },
+                }
+            })
+        });
+    }
+
+    /// A builder for constructing a [`Common`] in-process, for use in
+    /// integration tests
+    ///
+    /// Unlike [`run`], the builder does not read the command line or load
+    /// `.env` files, does not install a global tracing subscriber or panic
+    /// hook, and reports failures as errors rather than exiting the process.
+    /// Arguments not passed explicitly may still be read from the environment.
+    /// No signal handlers are installed either, so tests should request a
+    /// shutdown using [`Common::shutdown`] instead.
+    #[derive(Debug)]
+    pub struct CommonBuilder {
+        cfg: StartConfig,
+        args: Vec<OsString>,
+        log_filter: Cow<'static, str>,
+        subsystems: Subsystems,
+        #[cfg(feature = "credits")]
+        credit_sheet: Option<String>,
+    }
+
+    impl Common {
+        /// Construct a builder for creating a [`Common`] without going through
+        /// [`run`]
+        #[must_use]
+        pub fn builder(cfg: StartConfig) -> CommonBuilder {
+            CommonBuilder {
+                cfg,
+                args: vec![],
+                log_filter: Borrowed("debug"),
+                subsystems: Subsystems::ALL,
+                #[cfg(feature = "credits")]
+                credit_sheet: None,
+            }
+        }
+    }
+
+    impl CommonBuilder {
+        /// Append a command-line argument to parse options from
+        #[must_use]
+        pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+            self.args.push(arg.into());
+            self
+        }
+
+        /// Append several command-line arguments to parse options from
+        #[must_use]
+        pub fn args<I: IntoIterator>(mut self, args: I) -> Self
+        where
+            I::Item: Into<OsString>,
+        {
+            self.args.extend(args.into_iter().map(Into::into));
+            self
+        }
+
+        /// Set the initial directives for [`Common::log_filter`]
+        ///
+        /// No subscriber is installed by the builder, so this only affects
+        /// the value reported by the filter handle.
+        #[must_use]
+        pub fn log_filter(mut self, log_filter: impl Into<Cow<'static, str>>) -> Self {
+            self.log_filter = log_filter.into();
+            self
+        }
+
+        /// Set the subsystems to initialize, defaulting to
+        /// [`Subsystems::ALL`]
+        #[must_use]
+        pub fn subsystems(mut self, subsystems: Subsystems) -> Self {
+            self.subsystems = subsystems;
+            self
+        }
+
+        /// Write the given TOML credit sheet to a temporary file and pass it
+        /// as the credit sheet path, removing it when the returned harness is
+        /// dropped
+        #[cfg(feature = "credits")]
+        #[must_use]
+        pub fn credit_sheet(mut self, toml: impl Into<String>) -> Self {
+            self.credit_sheet = Some(toml.into());
+            self
+        }
+
+        /// Parse the given arguments and construct the service runtime
+        ///
+        /// # Errors
+        /// This method returns an error if the arguments are invalid or any
+        /// part of the runtime fails to initialize.
+        pub fn build<T: fmt::Debug + clap::Args>(self) -> Result<(Common, T, Harness)> {
+            use clap::FromArgMatches;
+
+            let Self {
+                cfg,
+                args,
+                log_filter,
+                subsystems,
+                #[cfg(feature = "credits")]
+                credit_sheet,
+            } = self;
+            let identity = Identity::new(&cfg)?;
+
+            #[cfg(feature = "credits")]
+            let credit_sheet = match credit_sheet {
+                Some(toml) => {
+                    let path = std::env::temp_dir().join(format!(
+                        "{}-{}-credits.toml",
+                        identity.service_name, identity.instance_id
+                    ));
+                    std::fs::write(&path, toml)
+                        .with_context(|| format!("Failed to write credit sheet to {path:?}"))?;
+
+                    Some(TempFile(path))
                 },
+                None => None,
             };
+            #[cfg(feature = "credits")]
+            let args = args.into_iter().chain(
+                credit_sheet
+                    .iter()
+                    .flat_map(|f| [OsString::from("--credit-sheet"), f.0.clone().into()]),
+            );
 
-            std::process::exit(match main(common, extra) {
-                Ok(()) => 0,
-                Err(e) => {
-                    error!("{e:?}");
-                    1
-                },
-            });
-        });
+            let cmd = <CommonArgs<T> as clap::Args>::augment_args(
+                clap::Command::new(identity.service_name).no_binary_name(true),
+            );
+            let common = cmd
+                .try_get_matches_from(args)
+                .and_then(|m| CommonArgs::<T>::from_arg_matches(&m))
+                .context("Failed to parse arguments")?;
+
+            let rt = build_runtime(common.jobs)?;
+
+            let (filter, handle) = reload::Layer::new(
+                EnvFilter::try_new(&log_filter)
+                    .with_context(|| format!("Invalid log filter {log_filter:?}"))?,
+            );
+            let telemetry = Telemetry {
+                log_filter: LogFilter::new(handle, &log_filter),
+                loki: None,
+                #[cfg(feature = "otlp")]
+                otlp: false,
+            };
+
+            let (lifecycle, log_filter) =
+                Lifecycle::new(rt, Duration::from_secs(common.shutdown_timeout), telemetry);
+            let (common, extra) =
+                Common::new(identity, &lifecycle, common, subsystems, log_filter)?;
+
+            Ok((common, extra, Harness {
+                lifecycle,
+                _filter: filter,
+                #[cfg(feature = "credits")]
+                _credit_sheet: credit_sheet,
+            }))
+        }
+    }
+
+    /// Resources backing a [`Common`] created by [`CommonBuilder`], which must
+    /// be kept alive for as long as the [`Common`] is in use
+    ///
+    /// The harness owns the Tokio runtime backing [`Common::rt`], so tests
+    /// using it should be plain `#[test]` functions that enter the runtime
+    /// with [`block_on`](tokio::runtime::Handle::block_on) rather than
+    /// `#[tokio::test]` functions.
+    ///
+    /// # Panics
+    /// Dropping a harness drops its runtime, which panics if done from within
+    /// an async context, as does calling [`teardown`](Self::teardown).
+    pub struct Harness {
+        lifecycle: Lifecycle,
+        _filter: reload::Layer<EnvFilter, tracing_subscriber::Registry>,
+        #[cfg(feature = "credits")]
+        _credit_sheet: Option<TempFile>,
+    }
+
+    impl fmt::Debug for Harness {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Harness").finish_non_exhaustive()
+        }
+    }
+
+    impl Harness {
+        /// Request a graceful shutdown and wait for registered tasks to finish,
+        /// as [`run`] does once its entrypoint returns
+        ///
+        /// # Panics
+        /// This method panics if called from within an async context, such as
+        /// a `#[tokio::test]` function.
+        pub fn teardown(self) {
+            self.lifecycle.teardown();
+        }
+    }
+
+    /// A file that is deleted when dropped
+    #[cfg(any(feature = "credits", test))]
+    #[derive(Debug)]
+    struct TempFile(PathBuf);
+
+    #[cfg(any(feature = "credits", test))]
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            if let Err(e) = std::fs::remove_file(&self.0) {
+                warn!("Failed to remove temporary file {:?}: {e}", self.0);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use clap::{CommandFactory, FromArgMatches};
+
+        use super::*;
+
+        #[derive(Debug, clap::Parser)]
+        struct TestOpts {
+            #[arg(long, env = "HUB_CORE_TEST_PRECEDENCE", default_value_t = 1)]
+            precedence: u32,
+
+            #[arg(long, default_value = "default")]
+            multi_word: String,
+
+            #[arg(long, value_enum, ignore_case = true)]
+            log_format: Option<LogFormat>,
+
+            #[arg(long)]
+            tag: Vec<String>,
+        }
+
+        fn config_file(name: &str, toml: &str) -> TempFile {
+            let path = std::env::temp_dir()
+                .join(format!("hub-core-test-{name}-{}.toml", std::process::id()));
+            std::fs::write(&path, toml).unwrap();
+
+            TempFile(path)
+        }
+
+        fn parse(config: Option<&TempFile>, args: &[&str]) -> Result<TestOpts> {
+            let mut cmd = TestOpts::command();
+
+            if let Some(TempFile(path)) = config {
+                cmd = apply_config_file(cmd, path)?;
+            }
+
+            let matches =
+                cmd.try_get_matches_from(std::iter::once("test").chain(args.iter().copied()))?;
+
+            Ok(TestOpts::from_arg_matches(&matches)?)
+        }
+
+        #[test]
+        fn config_file_precedence() {
+            let config = config_file("precedence", "precedence = 2");
+
+            assert_eq!(parse(None, &[]).unwrap().precedence, 1);
+            assert_eq!(parse(Some(&config), &[]).unwrap().precedence, 2);
+
+            std::env::set_var("HUB_CORE_TEST_PRECEDENCE", "3");
+            let from_env = parse(Some(&config), &[]).map(|o| o.precedence);
+            let from_args = parse(Some(&config), &["--precedence", "4"]).map(|o| o.precedence);
+            std::env::remove_var("HUB_CORE_TEST_PRECEDENCE");
+
+            assert_eq!(from_env.unwrap(), 3);
+            assert_eq!(from_args.unwrap(), 4);
+        }
+
+        #[test]
+        fn config_file_keys() {
+            let kebab = config_file("kebab", "multi-word = \"kebab\"");
+            let snake = config_file("snake", "multi_word = \"snake\"");
+            let unknown = config_file("unknown", "not-an-option = 1");
+
+            assert_eq!(parse(Some(&kebab), &[]).unwrap().multi_word, "kebab");
+            assert_eq!(parse(Some(&snake), &[]).unwrap().multi_word, "snake");
+
+            let err = parse(Some(&unknown), &[]).unwrap_err().to_string();
+            assert!(err.contains("not-an-option"), "{err}");
+        }
+
+        #[test]
+        fn config_file_values() {
+            let config = config_file("values", "log-format = \"JSON\"\ntag = [\"a\", \"b\"]");
+
+            let opts = parse(Some(&config), &[]).unwrap();
+            assert_eq!(opts.log_format, Some(LogFormat::Json));
+            assert_eq!(opts.tag, ["a", "b"]);
+
+            let opts = parse(Some(&config), &["--tag", "c"]).unwrap();
+            assert_eq!(opts.tag, ["c"]);
+        }
+
+        #[test]
+        fn config_file_invalid_value() {
+            let config = config_file("invalid", "precedence = \"four\"");
+
+            let err = format!("{:#}", parse(Some(&config), &[]).unwrap_err());
+            assert!(err.contains("\"precedence\""), "{err}");
+            assert!(err.contains(&format!("{:?}", config.0)), "{err}");
+
+            let config = config_file("invalid-enum", "log-format = \"xml\"");
+            assert!(parse(Some(&config), &[]).is_err());
+        }
+
+        #[test]
+        #[cfg(feature = "kafka_internal")]
+        fn kafka_config_hides_values() {
+            let prop = parse_kafka_config("sasl.password=hunter2").unwrap();
+            assert_eq!(prop.0, "sasl.password");
+            assert_eq!(prop.1 .0, "hunter2");
+
+            let debug = format!("{prop:?}");
+            assert!(!debug.contains("hunter2"), "{debug}");
+        }
+
+        #[test]
+        #[cfg(feature = "kafka_internal")]
+        fn kafka_boot_timeout_nonzero() {
+            #[derive(Debug, clap::Args)]
+            struct Empty {}
+
+            let err = <CommonArgs<Empty> as clap::Args>::augment_args(clap::Command::new("test"))
+                .try_get_matches_from(["test", "--kafka-boot-timeout", "0"])
+                .unwrap_err();
+
+            assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+            assert!(err.to_string().contains("--kafka-boot-timeout"), "{err}");
+        }
     }
 }
@@ -0,0 +1,469 @@
+//! Console log formatting and runtime control of the log filter
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+use tracing::{callsite::Identifier, field::Field};
+use tracing_subscriber::{
+    filter::{filter_fn, FilterExt},
+    fmt::{
+        format::{FormatEvent, FormatFields, Writer},
+        FmtContext,
+    },
+    layer::{Context, Filter},
+    registry::LookupSpan,
+    reload, EnvFilter, Layer, Registry,
+};
+
+use crate::prelude::*;
+
+/// Output format for console logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human-readable single-line output
+    #[default]
+    Full,
+    /// Human-readable multi-line output
+    Pretty,
+    /// Abbreviated single-line output
+    Compact,
+    /// Newline-delimited JSON objects
+    Json,
+}
+
+impl LogFormat {
+    /// Read the log format from the `LOG_FORMAT` environment variable,
+    /// ignoring invalid values
+    ///
+    /// This is used to format logs emitted before command-line arguments have
+    /// been parsed.
+    pub fn from_env() -> Self {
+        std::env::var("LOG_FORMAT")
+            .ok()
+            .and_then(|s| clap::ValueEnum::from_str(&s, true).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Construct a console log layer using the given format
+pub(crate) fn layer<S>(
+    format: LogFormat,
+    service_name: &'static str,
+) -> Box<dyn Layer<S> + Send + Sync + 'static>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let layer = tracing_subscriber::fmt::layer();
+
+    match format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_target(true)
+            .map_event_format(|f| WithServiceName {
+                inner: f,
+                service_name,
+            })
+            .boxed(),
+    }
+}
+
+/// JSON event formatter that adds a `service_name` key to each object
+struct WithServiceName<F> {
+    inner: F,
+    service_name: &'static str,
+}
+
+impl<S, N, F> FormatEvent<S, N> for WithServiceName<F>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> fmt::Result {
+        let mut buf = String::new();
+        self.inner.format_event(ctx, Writer::new(&mut buf), event)?;
+
+        match buf.strip_prefix('{') {
+            Some(rest) => write!(
+                writer,
+                "{{\"service_name\":{},{rest}",
+                serde_json::Value::from(self.service_name)
+            ),
+            None => writer.write_str(&buf),
+        }
+    }
+}
+
+impl<F> fmt::Debug for WithServiceName<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WithServiceName")
+            .field("service_name", &self.service_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Target of the summaries emitted by [`RateLimit`], which are never filtered
+/// or rate-limited
+const RATE_LIMIT_TARGET: &str = "log_rate_limit";
+
+/// Target of the events reporting panics, which are never rate-limited
+pub(crate) const PANIC_TARGET: &str = "panic";
+
+/// Number of times an event was logged and suppressed in the current window
+#[derive(Debug, Default)]
+struct EventCount {
+    logged: u32,
+    suppressed: u64,
+}
+
+/// Visitor recording the `message` field of an event
+struct Message(String);
+
+impl tracing::field::Visit for Message {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            value.clone_into(&mut self.0);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+/// A filter that suppresses events logged more than a set number of times
+/// per window, keyed by callsite and message
+///
+/// Reported panics are never suppressed, but repeated errors are, since a
+/// failing handler may log the same error on every retry.
+///
+/// A summary of the events suppressed in each window is logged by
+/// [`flush`](Self::flush).
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimit {
+    limit: u32,
+    window: Duration,
+    counts: Arc<Mutex<HashMap<(Identifier, String), EventCount>>>,
+}
+
+impl RateLimit {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            counts: Arc::default(),
+        }
+    }
+
+    /// The length of the window events are counted over
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Log a summary of the events suppressed in the current window and start
+    /// a new window
+    pub fn flush(&self) {
+        let counts =
+            std::mem::take(&mut *self.counts.lock().unwrap_or_else(PoisonError::into_inner));
+
+        for ((_, message), count) in counts {
+            if count.suppressed > 0 {
+                warn!(
+                    target: RATE_LIMIT_TARGET,
+                    suppressed = count.suppressed,
+                    window = ?self.window,
+                    "Suppressed {} repeats of log event {message:?}",
+                    count.suppressed,
+                );
+            }
+        }
+    }
+}
+
+impl<S: tracing::Subscriber> Filter<S> for RateLimit {
+    fn enabled(&self, _: &tracing::Metadata<'_>, _: &Context<'_, S>) -> bool {
+        true
+    }
+
+    fn event_enabled(&self, event: &tracing::Event<'_>, _: &Context<'_, S>) -> bool {
+        let meta = event.metadata();
+        if meta.target() == RATE_LIMIT_TARGET || meta.target() == PANIC_TARGET {
+            return true;
+        }
+
+        let mut message = Message(String::new());
+        event.record(&mut message);
+
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = counts.entry((meta.callsite(), message.0)).or_default();
+
+        if count.logged < self.limit {
+            count.logged += 1;
+            true
+        } else {
+            count.suppressed += 1;
+            false
+        }
+    }
+}
+
+/// Combine the log filter with an optional rate limit
+///
+/// Summaries logged by the rate limit bypass the log filter, so suppressed
+/// events are always accounted for.
+pub(crate) fn with_rate_limit<S: tracing::Subscriber>(
+    filter: impl Filter<S>,
+    rate_limit: Option<RateLimit>,
+) -> impl Filter<S> {
+    filter
+        .or(filter_fn(|meta| meta.target() == RATE_LIMIT_TARGET))
+        .and(rate_limit)
+}
+
+/// A handle for replacing the active log filter while the service is running
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    initial: Arc<str>,
+}
+
+impl fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogFilter")
+            .field("current", &self.current().ok())
+            .field("initial", &self.initial)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LogFilter {
+    pub(crate) fn new(handle: reload::Handle<EnvFilter, Registry>, initial: &str) -> Self {
+        Self {
+            handle,
+            initial: initial.into(),
+        }
+    }
+
+    /// Get the directives of the active log filter
+    ///
+    /// # Errors
+    /// This method returns an error if the subscriber owning the filter has
+    /// been dropped.
+    pub fn current(&self) -> Result<String> {
+        self.handle
+            .with_current(ToString::to_string)
+            .context("Failed to read log filter")
+    }
+
+    /// Replace the active log filter, using env_logger-like syntax
+    ///
+    /// # Errors
+    /// This method returns an error if the directives are invalid or the
+    /// subscriber owning the filter has been dropped.
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(directives)
+            .with_context(|| format!("Invalid log filter {directives:?}"))?;
+
+        self.handle
+            .reload(filter)
+            .context("Failed to reload log filter")?;
+
+        info!(filter = directives, "Log filter updated");
+        Ok(())
+    }
+
+    /// Restore the log filter the service was started with
+    ///
+    /// # Errors
+    /// This method returns an error if the subscriber owning the filter has
+    /// been dropped.
+    pub fn reset(&self) -> Result<()> {
+        self.set(&self.initial)
+    }
+
+    /// Reload the log filter each time the process receives `SIGHUP`
+    ///
+    /// The filter is read from the first of the given `.env` files to set
+    /// `RUST_LOG`, or reset to the filter the service was started with if none
+    /// do.
+    pub(crate) async fn reload_on_hangup(self, env_files: Vec<PathBuf>) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut sighup =
+                signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+
+            while sighup.recv().await.is_some() {
+                // Loading the files with dotenv::from_path would not override
+                // the RUST_LOG value already in the environment, so they are
+                // parsed without modifying it
+                #[allow(deprecated)]
+                let filter = env_files.iter().find_map(|p| {
+                    dotenv::from_filename_iter(p)
+                        .ok()?
+                        .filter_map(Result::ok)
+                        .find_map(|(k, v)| (k == "RUST_LOG").then_some(v))
+                });
+
+                let res = match filter {
+                    Some(f) => self.set(&f),
+                    None => self.reset(),
+                };
+
+                if let Err(e) = res {
+                    error!("Failed to reload log filter on SIGHUP: {e:?}");
+                }
+            }
+        }
+
+        #[cfg(not(unix))]
+        let _ = env_files;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::Layer;
+
+    use super::*;
+
+    /// Layer recording the target and message of each event it receives
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl Capture {
+        fn take(&self) -> Vec<(String, String)> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+            let mut message = Message(String::new());
+            event.record(&mut message);
+
+            self.0
+                .lock()
+                .unwrap()
+                .push((event.metadata().target().to_owned(), message.0));
+        }
+    }
+
+    fn events(target: &str, message: &str, n: usize) -> Vec<(String, String)> {
+        vec![(target.to_owned(), message.to_owned()); n]
+    }
+
+    #[test]
+    fn rate_limit() {
+        let target = module_path!();
+        let rate_limit = RateLimit::new(2, Duration::from_secs(60));
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(capture.clone().with_filter(with_rate_limit(
+            EnvFilter::new("warn"),
+            Some(rate_limit.clone()),
+        )));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let log = |message: &str| warn!("{message}");
+
+            for _ in 0..5 {
+                log("foo");
+            }
+            log("bar");
+            for _ in 0..4 {
+                error!("baz");
+            }
+            for _ in 0..3 {
+                error!(target: PANIC_TARGET, "Panicked: qux");
+            }
+
+            assert_eq!(
+                capture.take(),
+                [
+                    events(target, "foo", 2),
+                    events(target, "bar", 1),
+                    events(target, "baz", 2),
+                    events(PANIC_TARGET, "Panicked: qux", 3),
+                ]
+                .concat()
+            );
+
+            rate_limit.flush();
+            let mut summaries = capture.take();
+            summaries.sort();
+            assert_eq!(summaries, [
+                (
+                    RATE_LIMIT_TARGET.to_owned(),
+                    "Suppressed 2 repeats of log event \"baz\"".to_owned(),
+                ),
+                (
+                    RATE_LIMIT_TARGET.to_owned(),
+                    "Suppressed 3 repeats of log event \"foo\"".to_owned(),
+                ),
+            ]);
+
+            for _ in 0..3 {
+                log("foo");
+            }
+            assert_eq!(capture.take(), events(target, "foo", 2));
+
+            rate_limit.flush();
+            assert_eq!(capture.take(), [(
+                RATE_LIMIT_TARGET.to_owned(),
+                "Suppressed 1 repeats of log event \"foo\"".to_owned(),
+            )]);
+
+            rate_limit.flush();
+            assert_eq!(capture.take(), []);
+        });
+    }
+
+    #[test]
+    fn rate_limit_summary_bypasses_filter() {
+        let rate_limit = RateLimit::new(1, Duration::from_secs(60));
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(capture.clone().with_filter(with_rate_limit(
+            EnvFilter::new("warn"),
+            Some(rate_limit.clone()),
+        )));
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..2 {
+                warn!("foo");
+            }
+            assert_eq!(capture.take(), events(module_path!(), "foo", 1));
+
+            // The summary is logged at WARN, so raise the filter above it
+            // to check that it is still delivered
+            let subscriber = Registry::default().with(
+                capture
+                    .clone()
+                    .with_filter(with_rate_limit(EnvFilter::new("error"), None)),
+            );
+            tracing::subscriber::with_default(subscriber, || rate_limit.flush());
+
+            assert_eq!(capture.take(), [(
+                RATE_LIMIT_TARGET.to_owned(),
+                "Suppressed 1 repeats of log event \"foo\"".to_owned(),
+            )]);
+        });
+    }
+}
@@ -1,4 +1,5 @@
 //! metrics imports
+
 pub use opentelemetry::{
     metrics::{Counter, Histogram, MeterProvider as _, Unit},
     KeyValue,
@@ -9,3 +10,155 @@ pub use opentelemetry_sdk::{
     runtime, Resource,
 };
 pub use prometheus::{Encoder, Registry, TextEncoder};
+
+use crate::prelude::*;
+
+/// Construct the meter provider and Prometheus registry owned by the runtime,
+/// registering the provider as the global default
+pub(crate) fn init(identity: &crate::Identity) -> Result<(MeterProvider, Registry)> {
+    let registry = Registry::new();
+    let exporter = exporter()
+        .with_registry(registry.clone())
+        .build()
+        .context("Failed to build Prometheus exporter")?;
+
+    let provider = MeterProvider::builder()
+        .with_reader(exporter)
+        .with_resource(crate::otel::resource(identity))
+        .build();
+
+    opentelemetry::global::set_meter_provider(provider.clone());
+
+    Ok((provider, registry))
+}
+
+/// Register instruments reporting the worker, task, and queue statistics of
+/// the given Tokio runtime
+///
+/// Blocking pool and per-worker queue statistics are only available when
+/// built with `--cfg tokio_unstable`.
+pub(crate) fn register_runtime(
+    provider: &MeterProvider,
+    rt: &tokio::runtime::Handle,
+) -> Result<()> {
+    let meter = provider.meter("tokio");
+    let metrics = rt.metrics();
+
+    let workers = meter
+        .u64_observable_gauge("tokio.workers")
+        .with_description("Number of runtime worker threads")
+        .init();
+    let alive_tasks = meter
+        .u64_observable_gauge("tokio.alive_tasks")
+        .with_description("Number of tasks currently alive on the runtime")
+        .init();
+    let global_queue_depth = meter
+        .u64_observable_gauge("tokio.global_queue_depth")
+        .with_description("Number of tasks in the runtime's global queue")
+        .init();
+    let busy_time = meter
+        .f64_observable_counter("tokio.worker.busy_time")
+        .with_description("Total time each worker thread has spent busy")
+        .with_unit(Unit::new("s"))
+        .init();
+    let park_count = meter
+        .u64_observable_counter("tokio.worker.park_count")
+        .with_description("Number of times each worker thread has parked")
+        .init();
+    #[cfg(tokio_unstable)]
+    let local_queue_depth = meter
+        .u64_observable_gauge("tokio.worker.local_queue_depth")
+        .with_description("Number of tasks in each worker thread's local queue")
+        .init();
+    #[cfg(tokio_unstable)]
+    let blocking_threads = meter
+        .u64_observable_gauge("tokio.blocking_threads")
+        .with_description("Number of threads in the blocking pool")
+        .init();
+    #[cfg(tokio_unstable)]
+    let idle_blocking_threads = meter
+        .u64_observable_gauge("tokio.idle_blocking_threads")
+        .with_description("Number of idle threads in the blocking pool")
+        .init();
+    #[cfg(tokio_unstable)]
+    let blocking_queue_depth = meter
+        .u64_observable_gauge("tokio.blocking_queue_depth")
+        .with_description("Number of tasks waiting for a blocking pool thread")
+        .init();
+
+    let instruments = [
+        workers.as_any(),
+        alive_tasks.as_any(),
+        global_queue_depth.as_any(),
+        busy_time.as_any(),
+        park_count.as_any(),
+        #[cfg(tokio_unstable)]
+        local_queue_depth.as_any(),
+        #[cfg(tokio_unstable)]
+        blocking_threads.as_any(),
+        #[cfg(tokio_unstable)]
+        idle_blocking_threads.as_any(),
+        #[cfg(tokio_unstable)]
+        blocking_queue_depth.as_any(),
+    ];
+
+    // The registration is never unregistered, so it lives as long as the
+    // meter provider
+    meter
+        .register_callback(&instruments, move |obs| {
+            let num_workers = metrics.num_workers();
+
+            obs.observe_u64(&workers, num_workers as u64, &[]);
+            obs.observe_u64(&alive_tasks, metrics.num_alive_tasks() as u64, &[]);
+            obs.observe_u64(&global_queue_depth, metrics.global_queue_depth() as u64, &[
+            ]);
+            #[cfg(tokio_unstable)]
+            {
+                obs.observe_u64(&blocking_threads, metrics.num_blocking_threads() as u64, &[
+                ]);
+                obs.observe_u64(
+                    &idle_blocking_threads,
+                    metrics.num_idle_blocking_threads() as u64,
+                    &[],
+                );
+                obs.observe_u64(
+                    &blocking_queue_depth,
+                    metrics.blocking_queue_depth() as u64,
+                    &[],
+                );
+            }
+
+            for (worker, id) in (0..num_workers).zip(0_i64..) {
+                let attrs = [KeyValue::new("worker", id)];
+
+                obs.observe_f64(
+                    &busy_time,
+                    metrics.worker_total_busy_duration(worker).as_secs_f64(),
+                    &attrs,
+                );
+                obs.observe_u64(&park_count, metrics.worker_park_count(worker), &attrs);
+                #[cfg(tokio_unstable)]
+                obs.observe_u64(
+                    &local_queue_depth,
+                    metrics.worker_local_queue_depth(worker) as u64,
+                    &attrs,
+                );
+            }
+        })
+        .context("Failed to register Tokio runtime metrics")?;
+
+    Ok(())
+}
+
+/// Encode all metrics in the given registry using the Prometheus text format,
+/// returning the content type and body
+pub(crate) fn encode(registry: &Registry) -> Result<(String, Vec<u8>)> {
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+
+    encoder
+        .encode(&registry.gather(), &mut buf)
+        .context("Failed to encode Prometheus metrics")?;
+
+    Ok((encoder.format_type().into(), buf))
+}
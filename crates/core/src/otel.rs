@@ -0,0 +1,113 @@
+//! Shared OpenTelemetry setup for metrics and trace export
+
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::Resource;
+
+#[cfg(feature = "otlp")]
+use crate::prelude::*;
+use crate::Identity;
+
+/// Construct the resource identifying this service instance in exported
+/// telemetry
+pub(crate) fn resource(identity: &Identity) -> Resource {
+    Resource::new(
+        [
+            KeyValue::new("service.name", identity.service_name),
+            KeyValue::new("service.version", identity.version),
+            KeyValue::new("service.instance.id", identity.instance_id.to_string()),
+            KeyValue::new("host.name", identity.host_name.clone()),
+        ]
+        .into_iter()
+        .chain(
+            identity
+                .git_commit
+                .map(|c| KeyValue::new("vcs.repository.ref.revision", c)),
+        ),
+    )
+}
+
+/// Construct a tracer exporting spans to the given OTLP gRPC endpoint,
+/// registering its provider as the global default
+///
+/// This also registers the W3C trace context propagator as the global
+/// propagator used to carry traces across Kafka records, which services may
+/// replace after startup.
+///
+/// This must be called from within the context of a Tokio runtime.
+#[cfg(feature = "otlp")]
+pub(crate) fn tracer(
+    endpoint: &Url,
+    resource: Resource,
+) -> Result<opentelemetry_sdk::trace::Tracer> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+    );
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint.as_str()),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+        .context("Failed to install OTLP trace pipeline")
+}
+
+/// Inject the trace context of the current span using the global propagator
+#[cfg(all(feature = "otlp", feature = "kafka_internal"))]
+pub(crate) fn inject_current(injector: &mut dyn opentelemetry::propagation::Injector) {
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let cx = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|p| p.inject_context(&cx, injector));
+}
+
+/// Extract the trace context of the span that produced the given record using
+/// the global propagator
+#[cfg(all(feature = "otlp", feature = "kafka"))]
+pub(crate) fn extract<M: rdkafka::Message>(msg: &M) -> opentelemetry::Context {
+    use rdkafka::message::Headers;
+
+    struct HeaderExtractor<'a, H>(&'a H);
+
+    impl<'a, H: Headers> opentelemetry::propagation::Extractor for HeaderExtractor<'a, H> {
+        fn get(&self, key: &str) -> Option<&str> {
+            let headers: &'a H = self.0;
+
+            headers
+                .iter()
+                .find(|h| h.key == key)
+                .and_then(|h| std::str::from_utf8(h.value?).ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            let headers: &'a H = self.0;
+
+            headers.iter().map(|h| h.key).collect()
+        }
+    }
+
+    match msg.headers() {
+        Some(headers) => {
+            opentelemetry::global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)))
+        },
+        None => opentelemetry::Context::new(),
+    }
+}
+
+/// Flush any buffered spans and shut down the global tracer provider, giving
+/// up after the given timeout
+#[cfg(feature = "otlp")]
+pub(crate) async fn shutdown(timeout: Duration) {
+    let flush = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider);
+
+    match tokio::time::timeout(timeout, flush).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => error!("Failed to shut down OTLP tracer provider: {e}"),
+        Err(_) => warn!("Timed out flushing traces to the OTLP collector"),
+    }
+}
@@ -1,14 +1,32 @@
 //! A Kafka record producer
 
-use std::fmt;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::{prelude::*, util::DebugShim};
+use backon::ExponentialBuilder;
+use rdkafka::{
+    message::{Header, OwnedHeaders},
+    producer::Producer as _,
+};
+use uuid::Uuid;
+
+use crate::{
+    bus::{Bus, MemoryBus},
+    kafka::{ClientConfig, Context},
+    prelude::*,
+    shutdown::Shutdown,
+    util::DebugShim,
+};
 
 /// Service startup configuration for producing Kafka records
 #[derive(Debug, Clone)]
 pub struct Config {
     pub(crate) topic: String,
-    pub(crate) config: DebugShim<rdkafka::ClientConfig>,
+    pub(crate) identity: crate::Identity,
+    pub(crate) bus: Bus,
+    pub(crate) shutdown: Shutdown,
 }
 
 impl Config {
@@ -21,6 +39,87 @@ impl Config {
     pub async fn build<M: Message>(self) -> Result<Producer<M>> {
         Producer::new(self).await
     }
+
+    /// Adjust the backoff used to retry connecting to Kafka while building
+    /// the producer
+    #[must_use]
+    pub fn boot_backoff(
+        mut self,
+        f: impl FnOnce(ExponentialBuilder) -> ExponentialBuilder,
+    ) -> Self {
+        self.bus.boot_backoff(f);
+        self
+    }
+}
+
+/// Names of the headers attached to records by [`Producer`]
+pub mod header {
+    /// The name of the service that produced the record, attached to every
+    /// record
+    pub const SERVICE_NAME: &str = "service_name";
+    /// A UUID unique to the record, attached to every record
+    pub const MESSAGE_ID: &str = "message_id";
+    /// The RFC 3339 time the record was produced at, attached to every record
+    pub const PRODUCED_AT: &str = "produced_at";
+    /// An ID shared by records relating to the same request
+    pub const CORRELATION_ID: &str = "correlation_id";
+    /// The version of the schema the record payload was encoded with
+    pub const SCHEMA_VERSION: &str = "schema_version";
+}
+
+/// Additional headers to attach to an outgoing record
+///
+/// The [`SERVICE_NAME`](header::SERVICE_NAME),
+/// [`MESSAGE_ID`](header::MESSAGE_ID), and
+/// [`PRODUCED_AT`](header::PRODUCED_AT) headers are attached to every record
+/// automatically.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(Cow<'static, str>, Vec<u8>)>);
+
+impl Headers {
+    /// Construct an empty set of headers
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a header with the given name and value
+    #[must_use]
+    pub fn with(mut self, name: impl Into<Cow<'static, str>>, value: impl Into<Vec<u8>>) -> Self {
+        self.0.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add a [`CORRELATION_ID`](header::CORRELATION_ID) header
+    #[must_use]
+    pub fn correlation_id(self, id: Uuid) -> Self {
+        self.with(header::CORRELATION_ID, id.to_string())
+    }
+
+    /// Add a [`SCHEMA_VERSION`](header::SCHEMA_VERSION) header
+    #[must_use]
+    pub fn schema_version(self, version: impl fmt::Display) -> Self {
+        self.with(header::SCHEMA_VERSION, version.to_string())
+    }
+
+    /// Iterate over the name and value of each header
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.0.iter().map(|(k, v)| (k.as_ref(), v.as_slice()))
+    }
+}
+
+#[cfg(feature = "otlp")]
+impl opentelemetry::propagation::Injector for Headers {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.push((key.to_owned().into(), value.into_bytes()));
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Backend {
+    Kafka(DebugShim<rdkafka::producer::FutureProducer<Context>>),
+    Memory(MemoryBus),
 }
 
 /// A producer for emitting messages onto the Kafka topic identified by this
@@ -28,63 +127,146 @@ impl Config {
 #[derive(Debug, Clone)]
 pub struct Producer<M> {
     topic: String,
-    producer: DebugShim<rdkafka::producer::FutureProducer>,
+    service_name: &'static str,
+    instance_id: Uuid,
+    backend: Backend,
     msg: PhantomData<fn(&M)>,
 }
 
+/// Create the given topic and a Kafka producer to send records to it
+async fn create_kafka(
+    topic: &str,
+    config: &ClientConfig,
+    shutdown: &Shutdown,
+) -> Result<rdkafka::producer::FutureProducer<Context>> {
+    let retry = &config.boot_retry;
+    let admin = config
+        .create_admin()
+        .context("Failed to create Kafka admin client")?;
+
+    retry
+        .run("Creating Kafka topic", |remaining| {
+            let admin = &admin;
+
+            async move {
+                admin
+                    .create_topics(
+                        &[rdkafka::admin::NewTopic {
+                            name: topic,
+                            config: vec![],
+                            num_partitions: 1,
+                            replication: rdkafka::admin::TopicReplication::Fixed(1),
+                        }],
+                        &rdkafka::admin::AdminOptions::new()
+                            .request_timeout(Some(remaining))
+                            .operation_timeout(Some(remaining)),
+                    )
+                    .await
+                    .context("Failed to create test topic")
+            }
+        })
+        .await?;
+
+    // Creating the topic already waited for the brokers to be reachable
+    let producer: rdkafka::producer::FutureProducer<_> =
+        config.create().context("Failed to create Kafka producer")?;
+
+    shutdown.spawn({
+        let producer = producer.clone();
+        let shutdown = shutdown.clone();
+
+        async move {
+            shutdown.triggered().await;
+
+            let timeout = shutdown.timeout();
+            match tokio::task::spawn_blocking(move || producer.flush(timeout)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => error!("Failed to flush Kafka producer: {e}"),
+                Err(e) => error!("Failed to join Kafka producer flush task: {e}"),
+            }
+        }
+    });
+
+    Ok(producer)
+}
+
 impl<M: Message> Producer<M> {
     #[instrument(name = "build_producer")]
     pub(crate) async fn new(config: Config) -> Result<Self> {
-        let admin: rdkafka::admin::AdminClient<_> = config
-            .config
-            .0
-            .create()
-            .context("Failed to create Kafka admin client")?;
-
-        admin
-            .create_topics(
-                &[rdkafka::admin::NewTopic {
-                    name: &config.topic,
-                    config: vec![],
-                    num_partitions: 1,
-                    replication: rdkafka::admin::TopicReplication::Fixed(1),
-                }],
-                &rdkafka::admin::AdminOptions::new(),
-            )
-            .await
-            .context("Failed to create test topic")?;
+        let Config {
+            topic,
+            identity,
+            bus,
+            shutdown,
+        } = config;
 
-        let producer = config
-            .config
-            .0
-            .create()
-            .context("Failed to create Kafka producer")?;
+        let backend = match bus {
+            Bus::Kafka(config) => {
+                Backend::Kafka(DebugShim(create_kafka(&topic, &config, &shutdown).await?))
+            },
+            Bus::Memory(bus) => Backend::Memory(bus),
+            Bus::Disabled => bail!("Kafka is not initialized for this command"),
+        };
 
         Ok(Self {
-            topic: config.topic,
-            producer: DebugShim(producer),
+            topic,
+            service_name: identity.service_name,
+            instance_id: identity.instance_id,
+            backend,
             msg: PhantomData::default(),
         })
     }
 
     /// Send a single record to the Kafka broker
-    #[instrument(level = "debug")]
+    #[inline]
     pub async fn send(
         &self,
         payload: Option<&M>, // TODO: don't wrap this in Option
         key: Option<&M::Key>,
     ) -> Result<(), SendError> {
-        match self
-            .producer
-            .0
+        self.send_with_headers(payload, key, Headers::new()).await
+    }
+
+    /// Send a single record to the Kafka broker with the given additional
+    /// headers
+    ///
+    /// If trace export is enabled, the trace context of the current span is
+    /// also attached using the global OpenTelemetry propagator.
+    #[instrument(level = "debug")]
+    pub async fn send_with_headers(
+        &self,
+        payload: Option<&M>,
+        key: Option<&M::Key>,
+        headers: Headers,
+    ) -> Result<(), SendError> {
+        let payload = payload.map(prost::Message::encode_to_vec);
+        let key = key.map(prost::Message::encode_to_vec);
+        #[cfg(feature = "otlp")]
+        let headers = {
+            let mut headers = headers;
+            crate::otel::inject_current(&mut headers);
+            headers
+        };
+        let headers = self.record_headers(&headers);
+
+        let producer = match self.backend {
+            Backend::Kafka(ref p) => &p.0,
+            Backend::Memory(ref bus) => {
+                let (partition, offset) = bus.send(&self.topic, payload, key, headers);
+                trace!(partition, offset, "Message delivered");
+                return Ok(());
+            },
+        };
+
+        match producer
             .send(
                 rdkafka::producer::FutureRecord {
                     topic: &self.topic,
                     partition: None,
-                    payload: payload.map(prost::Message::encode_to_vec).as_deref(),
-                    key: key.map(prost::Message::encode_to_vec).as_deref(),
+                    payload: payload.as_deref(),
+                    key: key.as_deref(),
                     timestamp: None,
-                    headers: None,
+                    headers: Some(headers),
                 },
                 None,
             )
@@ -99,6 +281,39 @@ impl<M: Message> Producer<M> {
 
         Ok(())
     }
+
+    /// Construct the headers for a record, prepending the headers attached to
+    /// every record to the given headers
+    fn record_headers(&self, headers: &Headers) -> OwnedHeaders {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+        // IDs are unique within this process, and the instance ID makes them
+        // unique across processes
+        let (hi, lo) = self.instance_id.as_u64_pair();
+        let message_id = Uuid::from_u64_pair(
+            hi,
+            lo.wrapping_add(SEQUENCE.fetch_add(1, Ordering::Relaxed)),
+        )
+        .to_string();
+        let produced_at = chrono::Utc::now().to_rfc3339();
+
+        [
+            (header::SERVICE_NAME, self.service_name.as_bytes()),
+            (header::MESSAGE_ID, message_id.as_bytes()),
+            (header::PRODUCED_AT, produced_at.as_bytes()),
+        ]
+        .into_iter()
+        .chain(headers.iter())
+        .fold(
+            OwnedHeaders::new_with_capacity(headers.0.len() + 3),
+            |h, (key, value)| {
+                h.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            },
+        )
+    }
 }
 
 /// An error originating from an outgoing Kafka record
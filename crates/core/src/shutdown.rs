@@ -0,0 +1,160 @@
+//! Coordination for gracefully shutting down a service
+
+use std::sync::OnceLock;
+
+use tokio::{task::JoinHandle, time::Instant};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::prelude::*;
+
+/// A handle for requesting and observing a graceful shutdown of the service
+///
+/// Shutdown is requested when the process receives `SIGINT` or `SIGTERM`, or
+/// when the service entry point returns.  Tasks registered with
+/// [`spawn`](Self::spawn) or [`track`](Self::track) are given until the
+/// configured drain deadline to finish before the process exits.  If the
+/// service entry point has not returned by the deadline, buffered logs are
+/// flushed and the process exits anyway.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+    timeout: Duration,
+    deadline: Arc<OnceLock<Instant>>,
+}
+
+impl Shutdown {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            token: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+            timeout,
+            deadline: Arc::default(),
+        }
+    }
+
+    /// Request a graceful shutdown of the service, starting the drain
+    /// deadline if it has not already started
+    #[inline]
+    pub fn trigger(&self) {
+        self.deadline.get_or_init(|| Instant::now() + self.timeout);
+        self.token.cancel();
+    }
+
+    /// Returns true if a shutdown has been requested
+    #[inline]
+    #[must_use]
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Wait until a shutdown has been requested
+    #[inline]
+    pub async fn triggered(&self) {
+        self.token.cancelled().await;
+    }
+
+    /// Wait until the drain deadline has elapsed after a shutdown was
+    /// requested
+    pub async fn expired(&self) {
+        self.triggered().await;
+
+        if let Some(&deadline) = self.deadline.get() {
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+
+    /// Get the instant by which registered tasks must finish, or `None` if no
+    /// shutdown has been requested yet
+    #[inline]
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.get().copied()
+    }
+
+    /// Get a cancellation token that is cancelled when a shutdown is requested
+    #[inline]
+    #[must_use]
+    pub fn token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// Get the maximum time registered tasks are given to finish once a
+    /// shutdown has been requested
+    #[inline]
+    #[must_use]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Spawn a task on the current Tokio runtime that must complete before the
+    /// process exits
+    ///
+    /// # Panics
+    /// This method panics if called outside the context of a Tokio runtime.
+    #[inline]
+    pub fn spawn<F>(&self, fut: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tracker.spawn(fut)
+    }
+
+    /// Wrap a future such that the process waits for it to complete before
+    /// exiting
+    #[inline]
+    pub fn track<F: Future>(&self, fut: F) -> impl Future<Output = F::Output> {
+        self.tracker.track_future(fut)
+    }
+
+    /// Listen for termination signals, requesting a shutdown when one is
+    /// received and exiting immediately if one arrives while already shutting
+    /// down
+    pub(crate) async fn listen(self) -> Result<()> {
+        #[cfg(unix)]
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .context("Failed to install SIGTERM handler")?;
+
+        loop {
+            #[cfg(unix)]
+            let sigterm = sigterm.recv();
+            #[cfg(not(unix))]
+            let sigterm = std::future::pending::<Option<()>>();
+
+            let signal = tokio::select! {
+                r = tokio::signal::ctrl_c() => {
+                    r.context("Failed to listen for SIGINT")?;
+                    "SIGINT"
+                },
+                _ = sigterm => "SIGTERM",
+            };
+
+            if self.is_triggered() {
+                warn!(
+                    signal,
+                    "Received termination signal while shutting down, exiting now"
+                );
+                std::process::exit(1);
+            }
+
+            info!(signal, timeout = ?self.timeout, "Shutdown requested, draining tasks");
+            self.trigger();
+        }
+    }
+
+    /// Request a shutdown and wait until the drain deadline for all registered
+    /// tasks to complete, returning false if the deadline elapsed
+    ///
+    /// If a shutdown was already requested, the deadline started when it was
+    /// first requested rather than when this is called.
+    pub(crate) async fn drain(&self) -> bool {
+        self.trigger();
+        self.tracker.close();
+
+        tokio::select! {
+            () = self.tracker.wait() => true,
+            () = self.expired() => false,
+        }
+    }
+}
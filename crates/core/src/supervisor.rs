@@ -0,0 +1,388 @@
+//! Supervision of named long-lived background tasks
+
+use std::{
+    panic::AssertUnwindSafe,
+    sync::{Mutex, PoisonError},
+};
+
+use backon::{BackoffBuilder, ExponentialBuilder};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::{health::Health, prelude::*, shutdown::Shutdown};
+
+/// How long a supervised task must run before failing for its restart
+/// backoff to be reset
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+/// What to do when a supervised task fails or panics
+#[derive(Debug, Clone)]
+pub enum RestartPolicy {
+    /// Leave the task stopped and report it as failed
+    Never,
+    /// Restart the task after a delay drawn from the given backoff, giving up
+    /// once the backoff is exhausted
+    ///
+    /// The backoff starts over whenever the task fails after having run for
+    /// at least a minute, so only consecutive quick failures exhaust it.
+    OnFailure(ExponentialBuilder),
+    /// Abort the process
+    Abort,
+}
+
+impl Default for RestartPolicy {
+    /// Restart the task indefinitely with a jittered exponential backoff
+    fn default() -> Self {
+        Self::OnFailure(
+            ExponentialBuilder::default()
+                .with_jitter()
+                .with_max_times(usize::MAX),
+        )
+    }
+}
+
+/// The current state of a supervised task
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskState {
+    /// The task is running
+    Running,
+    /// The task failed and is waiting to be restarted
+    Restarting {
+        /// The number of times the task has failed in quick succession
+        attempt: usize,
+        /// The error the task last failed with
+        error: String,
+    },
+    /// The task failed and will not be restarted
+    Failed(String),
+    /// The task returned successfully before a shutdown was requested
+    Exited,
+    /// The task stopped because a shutdown was requested
+    Stopped,
+}
+
+type Tasks = Vec<(Cow<'static, str>, Arc<Mutex<TaskState>>)>;
+
+/// A registry of named background tasks, each restarted according to its
+/// [`RestartPolicy`] and reported as a readiness check
+///
+/// Supervised tasks are passed a cancellation token that is cancelled when a
+/// shutdown is requested, and are given until the drain deadline to finish.
+#[derive(Clone)]
+pub struct Supervisor {
+    rt: tokio::runtime::Handle,
+    shutdown: Shutdown,
+    health: Health,
+    tasks: Arc<Mutex<Tasks>>,
+}
+
+impl fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("tasks", &self.tasks())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Supervisor {
+    pub(crate) fn new(rt: tokio::runtime::Handle, shutdown: Shutdown, health: Health) -> Self {
+        Self {
+            rt,
+            shutdown,
+            health,
+            tasks: Arc::default(),
+        }
+    }
+
+    /// Spawn a named task, calling `task` again to restart it according to
+    /// the given policy whenever it fails or panics
+    pub fn spawn<F, R>(&self, name: impl Into<Cow<'static, str>>, policy: RestartPolicy, task: F)
+    where
+        F: FnMut(CancellationToken) -> R + Send + 'static,
+        R: Future<Output = Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        let state = Arc::new(Mutex::new(TaskState::Running));
+
+        self.tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((name.clone(), Arc::clone(&state)));
+
+        self.health.add_check(format!("task:{name}"), {
+            let state = Arc::clone(&state);
+            move || {
+                let state = state.lock().unwrap_or_else(PoisonError::into_inner).clone();
+                async move {
+                    match state {
+                        TaskState::Running | TaskState::Stopped => Ok(()),
+                        TaskState::Restarting { attempt, error } => {
+                            bail!("Restarting after {attempt} failure(s): {error}")
+                        },
+                        TaskState::Failed(e) => bail!("Failed: {e}"),
+                        TaskState::Exited => bail!("Exited unexpectedly"),
+                    }
+                }
+            }
+        });
+
+        let _guard = self.rt.enter();
+        self.shutdown
+            .spawn(supervise(name, policy, task, state, self.shutdown.token()));
+    }
+
+    /// Get the name and current state of each supervised task
+    #[must_use]
+    pub fn tasks(&self) -> Vec<(String, TaskState)> {
+        self.tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(n, s)| {
+                let state = s.lock().unwrap_or_else(PoisonError::into_inner).clone();
+                (n.to_string(), state)
+            })
+            .collect()
+    }
+}
+
+async fn supervise<F, R>(
+    name: Cow<'static, str>,
+    policy: RestartPolicy,
+    mut task: F,
+    state: Arc<Mutex<TaskState>>,
+    token: CancellationToken,
+) where
+    F: FnMut(CancellationToken) -> R,
+    R: Future<Output = Result<()>>,
+{
+    let set_state = |s| *state.lock().unwrap_or_else(PoisonError::into_inner) = s;
+    let build_backoff = || match &policy {
+        RestartPolicy::OnFailure(b) => Some(b.build()),
+        RestartPolicy::Never | RestartPolicy::Abort => None,
+    };
+    let mut backoff = build_backoff();
+    let mut attempt = 0;
+
+    loop {
+        set_state(TaskState::Running);
+
+        let started = Instant::now();
+        let res = AssertUnwindSafe(task(token.clone()))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Task panicked")));
+
+        if token.is_cancelled() {
+            if let Err(e) = res {
+                warn!(task = %name, "Task failed while shutting down: {e:?}");
+            }
+
+            set_state(TaskState::Stopped);
+            break;
+        }
+
+        let err = match res {
+            Ok(()) => {
+                warn!(task = %name, "Task exited before shutdown was requested");
+                set_state(TaskState::Exited);
+                break;
+            },
+            Err(e) => e,
+        };
+
+        // Earlier failures are unrelated to one after a long healthy run
+        if started.elapsed() >= HEALTHY_RUN {
+            attempt = 0;
+            backoff = build_backoff();
+        }
+
+        attempt += 1;
+        error!(task = %name, attempt, "Task failed: {err:?}");
+
+        if let RestartPolicy::Abort = policy {
+            error!(task = %name, "Aborting process due to failed task");
+            std::process::abort();
+        }
+
+        let Some(delay) = backoff.as_mut().and_then(Iterator::next) else {
+            set_state(TaskState::Failed(format!("{err:#}")));
+            break;
+        };
+
+        set_state(TaskState::Restarting {
+            attempt,
+            error: format!("{err:#}"),
+        });
+        info!(task = %name, ?delay, "Restarting task");
+
+        tokio::select! {
+            () = tokio::time::sleep(delay) => (),
+            () = token.cancelled() => {
+                set_state(TaskState::Stopped);
+                break;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn supervisor() -> (Supervisor, Shutdown, Health) {
+        let shutdown = Shutdown::new(Duration::from_secs(30));
+        let health = Health::default();
+        let supervisor = Supervisor::new(
+            tokio::runtime::Handle::current(),
+            shutdown.clone(),
+            health.clone(),
+        );
+
+        (supervisor, shutdown, health)
+    }
+
+    fn backoff(max_times: usize) -> RestartPolicy {
+        RestartPolicy::OnFailure(
+            ExponentialBuilder::default()
+                .with_min_delay(Duration::from_secs(1))
+                .with_max_times(max_times),
+        )
+    }
+
+    async fn state(supervisor: &Supervisor, health: &Health) -> (TaskState, Result<(), String>) {
+        let [(_, state)] = &supervisor.tasks()[..] else {
+            panic!("Expected exactly one task");
+        };
+        let report = health.check().await;
+        let [(_, res)] = &report.results().collect::<Vec<_>>()[..] else {
+            panic!("Expected exactly one check");
+        };
+
+        (state.clone(), res.map_err(ToOwned::to_owned))
+    }
+
+    fn restarting(attempt: usize) -> TaskState {
+        TaskState::Restarting {
+            attempt,
+            error: "boom".into(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_until_backoff_exhausted() {
+        let (supervisor, _shutdown, health) = supervisor();
+        supervisor.spawn("task", backoff(2), |_| async { bail!("boom") });
+
+        // Delays of 1s and 2s before giving up on the third failure
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(
+            state(&supervisor, &health).await,
+            (
+                restarting(1),
+                Err("Restarting after 1 failure(s): boom".into())
+            )
+        );
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(state(&supervisor, &health).await.0, restarting(2));
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            state(&supervisor, &health).await,
+            (TaskState::Failed("boom".into()), Err("Failed: boom".into()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn healthy_run_resets_backoff() {
+        let (supervisor, _shutdown, health) = supervisor();
+        let runs = Arc::new(AtomicUsize::new(0));
+        supervisor.spawn("task", backoff(1), {
+            let runs = Arc::clone(&runs);
+            move |_| {
+                let run = runs.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    // Only the second run stays up long enough to be healthy
+                    if run == 1 {
+                        tokio::time::sleep(HEALTHY_RUN).await;
+                    }
+
+                    bail!("boom")
+                }
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(state(&supervisor, &health).await.0, restarting(1));
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(
+            state(&supervisor, &health).await,
+            (TaskState::Running, Ok(()))
+        );
+
+        // The single delay in the backoff was used up by the first failure
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(state(&supervisor, &health).await.0, restarting(1));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            state(&supervisor, &health).await,
+            (TaskState::Failed("boom".into()), Err("Failed: boom".into()))
+        );
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_stops_task() {
+        let (supervisor, shutdown, health) = supervisor();
+        supervisor.spawn("task", RestartPolicy::default(), |token| async move {
+            token.cancelled().await;
+            Ok(())
+        });
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(
+            state(&supervisor, &health).await,
+            (TaskState::Running, Ok(()))
+        );
+
+        shutdown.trigger();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(
+            state(&supervisor, &health).await,
+            (TaskState::Stopped, Ok(()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_stops_restart() {
+        let (supervisor, shutdown, health) = supervisor();
+        supervisor.spawn("task", backoff(1), |_| async { bail!("boom") });
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(state(&supervisor, &health).await.0, restarting(1));
+
+        shutdown.trigger();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            state(&supervisor, &health).await,
+            (TaskState::Stopped, Ok(()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exit_fails_readiness() {
+        let (supervisor, _shutdown, health) = supervisor();
+        supervisor.spawn("task", RestartPolicy::default(), |_| async { Ok(()) });
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(
+            state(&supervisor, &health).await,
+            (TaskState::Exited, Err("Exited unexpectedly".into()))
+        );
+    }
+}
@@ -1,6 +1,6 @@
 //! Provides the [`Triage`] trait for getting info about an error's severity.
 
-use std::error::Error;
+use std::{error::Error, fmt};
 
 pub use hub_core_macros::Triage;
 
@@ -16,6 +16,95 @@ pub enum Severity {
     Fatal,
 }
 
+/// Exit code used by [`run`](crate::run) when the service fails with an error
+/// whose severity is unknown
+pub const EXIT_UNKNOWN: i32 = 1;
+
+impl Severity {
+    /// The exit code used by [`run`](crate::run) when the service fails with
+    /// an error of this severity
+    ///
+    /// | Severity      | Code | Meaning                                  |
+    /// |---------------|------|------------------------------------------|
+    /// | [`Transient`] | 75   | Restarting the service may succeed       |
+    /// | [`Permanent`] | 76   | Restarting the service will likely fail  |
+    /// | [`Fatal`]     | 77   | The service should not be restarted      |
+    ///
+    /// Errors whose severity is unknown exit with [`EXIT_UNKNOWN`].
+    ///
+    /// [`Transient`]: Self::Transient
+    /// [`Permanent`]: Self::Permanent
+    /// [`Fatal`]: Self::Fatal
+    #[must_use]
+    pub const fn exit_code(self) -> i32 {
+        match self {
+            Self::Transient => 75,
+            Self::Permanent => 76,
+            Self::Fatal => 77,
+        }
+    }
+}
+
+/// Find the severity of the outermost error in the chain of the given error
+/// that implements [`Triage`]
+///
+/// Because trait objects cannot be downcast, an error is only recognized if
+/// it is wrapped in a [`Triaged`] (or [`BoxedSync`]), or if it is one of the
+/// foreign or crate-provided error types with a [`Triage`] implementation in
+/// this crate.  Services should convert their own error types with
+/// [`TriageExt::triaged`] or [`Triaged::new`] before converting them into an
+/// [`anyhow::Error`].
+#[must_use]
+pub fn find_severity(err: &anyhow::Error) -> Option<Severity> {
+    macro_rules! downcast {
+        ($err:expr, $($(#[$meta:meta])* $ty:ty),* $(,)?) => {
+            $(
+                $(#[$meta])*
+                if let Some(e) = $err.downcast_ref::<$ty>() {
+                    return Some(e.severity());
+                }
+            )*
+        };
+    }
+
+    for err in err.chain() {
+        // Wrapped errors come first; the remaining types must be kept in sync
+        // with the implementations of Triage in this crate
+        downcast!(
+            err,
+            Triaged,
+            BoxedSync<'static>,
+            bs58::decode::Error,
+            std::io::Error,
+            #[cfg(feature = "jsonrpsee-core")]
+            jsonrpsee_core::Error,
+            prost::DecodeError,
+            #[cfg(feature = "rdkafka")]
+            rdkafka::error::RDKafkaErrorCode,
+            #[cfg(feature = "rdkafka")]
+            rdkafka::error::KafkaError,
+            reqwest::Error,
+            #[cfg(feature = "sea-orm")]
+            sea_orm::error::DbErr,
+            #[cfg(feature = "solana-client")]
+            solana_client::client_error::ClientError,
+            #[cfg(feature = "solana-sdk")]
+            solana_sdk::pubkey::ParsePubkeyError,
+            #[cfg(feature = "solana-sdk")]
+            solana_sdk::signature::ParseSignatureError,
+            uuid::Error,
+            #[cfg(feature = "kafka")]
+            crate::consumer::RecvError,
+            #[cfg(feature = "kafka_internal")]
+            crate::producer::SendError,
+            #[cfg(feature = "credits")]
+            crate::credits::DeductionErrorKind,
+        );
+    }
+
+    None
+}
+
 /// Type alias for a boxed [`dyn Triage`](Triage)
 pub type Boxed<'a> = Box<dyn Triage + 'a>;
 /// Type alias for a boxed [`dyn Triage`](Triage) with [`Send`] and [`Sync`]
@@ -27,6 +116,93 @@ pub trait Triage: Error {
     fn severity(&self) -> Severity;
 }
 
+/// An error wrapper that preserves the [`Severity`] of the wrapped error when
+/// it is converted into an [`anyhow::Error`]
+///
+/// The display, debug, and source of the wrapped error are forwarded
+/// unchanged, so wrapping an error does not alter how it is reported.
+pub struct Triaged(BoxedSync<'static>);
+
+impl Triaged {
+    /// Wrap an error so that [`find_severity`] can recover its severity
+    #[inline]
+    #[must_use]
+    pub fn new<E: Triage + Send + Sync + 'static>(err: E) -> Self {
+        Self(Box::new(err))
+    }
+
+    /// Unwrap the wrapped error
+    #[inline]
+    #[must_use]
+    pub fn into_inner(self) -> BoxedSync<'static> {
+        self.0
+    }
+}
+
+impl fmt::Debug for Triaged {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for Triaged {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl Error for Triaged {
+    #[inline]
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
+
+impl Triage for Triaged {
+    #[inline]
+    fn severity(&self) -> Severity {
+        self.0.severity()
+    }
+}
+
+/// Extension trait for wrapping the error of a [`Result`] in a [`Triaged`]
+///
+/// ```
+/// # use holaplex_hub_core::{prelude::*, triage::find_severity};
+/// #[derive(Debug, thiserror::Error, Triage)]
+/// #[error("Upstream is unavailable")]
+/// #[transient]
+/// struct Unavailable;
+///
+/// fn call() -> Result<(), Unavailable> {
+///     Err(Unavailable)
+/// }
+///
+/// fn main_loop() -> Result<()> {
+///     call().triaged().context("Failed to call upstream")?;
+///     Ok(())
+/// }
+///
+/// let err = main_loop().unwrap_err();
+/// assert_eq!(find_severity(&err), Some(Severity::Transient));
+/// ```
+pub trait TriageExt<T> {
+    /// Wrap the error, if any, in a [`Triaged`] to preserve its severity
+    ///
+    /// # Errors
+    /// This function returns the wrapped error if `self` is an `Err`.
+    fn triaged(self) -> Result<T, Triaged>;
+}
+
+impl<T, E: Triage + Send + Sync + 'static> TriageExt<T> for Result<T, E> {
+    #[inline]
+    fn triaged(self) -> Result<T, Triaged> {
+        self.map_err(Triaged::new)
+    }
+}
+
 impl Triage for bs58::decode::Error {
     #[inline]
     fn severity(&self) -> Severity {
@@ -0,0 +1,123 @@
+//! Runs a service runtime in-process over the in-memory event bus
+
+#![cfg(feature = "kafka")]
+
+use std::collections::HashMap;
+
+use holaplex_hub_core::{
+    clap,
+    consumer::{self, Consumer, Headers as _, MessageGroup, RecvError},
+    prelude::*,
+    producer::{self, header, Headers, Producer},
+    start_config,
+    uuid::Uuid,
+    Common,
+};
+
+const SERVICE_NAME: &str = "harness-test";
+
+#[derive(Debug, clap::Args)]
+struct Args {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Ping {
+    #[prost(string, tag = "1")]
+    text: String,
+}
+
+impl producer::Message for Ping {
+    type Key = Ping;
+}
+
+#[derive(Debug, Clone)]
+struct Received {
+    topic: String,
+    ping: Ping,
+    key: Ping,
+    headers: HashMap<String, Vec<u8>>,
+}
+
+impl MessageGroup for Received {
+    const REQUESTED_TOPICS: &'static [&'static str] = &[SERVICE_NAME];
+
+    fn from_message<M: consumer::Message>(msg: &M) -> Result<Self, RecvError> {
+        let ping = Ping::decode(msg.payload().ok_or(RecvError::MissingPayload)?)?;
+        let key = Ping::decode(msg.key().ok_or(RecvError::MissingKey)?)?;
+        let headers = msg
+            .headers()
+            .map(|h| {
+                h.iter()
+                    .map(|h| (h.key.to_owned(), h.value.unwrap_or_default().to_vec()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            topic: msg.topic().to_owned(),
+            ping,
+            key,
+            headers,
+        })
+    }
+}
+
+#[test]
+fn memory_bus_round_trip() {
+    let builder =
+        Common::builder(start_config!(SERVICE_NAME)).args(["--kafka-brokers", "memory://"]);
+    #[cfg(feature = "credits")]
+    let builder = builder.credit_sheet("[foo]\nsolana = 5\n");
+    #[cfg(feature = "asset_proxy")]
+    let builder = builder.args(["--asset-cdn", "https://example.com/"]);
+
+    let (common, Args {}, harness) = builder.build().unwrap();
+    let correlation_id = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+
+    let received = common.rt.clone().block_on(async move {
+        let producer: Producer<Ping> = common.producer_cfg.clone().build().await.unwrap();
+        let consumer: Consumer<Received> = common.consumer_cfg.build().await.unwrap();
+
+        // Subscribe before sending, since the in-memory bus does not retain
+        // records sent before a consumer subscribes
+        let mut stream = unsafe { consumer.to_stream() };
+
+        producer
+            .send_with_headers(
+                Some(&Ping {
+                    text: "hello".into(),
+                }),
+                Some(&Ping { text: "key".into() }),
+                Headers::new().correlation_id(correlation_id),
+            )
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("Timed out waiting for record")
+            .expect("Record stream hung up")
+            .unwrap()
+    });
+
+    assert_eq!(received.topic, SERVICE_NAME);
+    assert_eq!(received.ping.text, "hello");
+    assert_eq!(received.key.text, "key");
+    assert_eq!(
+        received
+            .headers
+            .get(header::SERVICE_NAME)
+            .map(Vec::as_slice),
+        Some(SERVICE_NAME.as_bytes())
+    );
+    assert_eq!(
+        received
+            .headers
+            .get(header::CORRELATION_ID)
+            .map(Vec::as_slice),
+        Some(correlation_id.to_string().as_bytes())
+    );
+    assert!(received.headers.contains_key(header::MESSAGE_ID));
+    assert!(received.headers.contains_key(header::PRODUCED_AT));
+
+    harness.teardown();
+}
@@ -0,0 +1,108 @@
+//! Checks that a service whose entry point ignores shutdown requests still
+//! exits once the drain deadline elapses after a termination signal
+//!
+//! This test re-runs its own binary as the service, so it does not use the
+//! default test harness.
+
+use std::{
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Command, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use holaplex_hub_core::{clap, start_config};
+
+/// Environment variable marking the re-run binary as the service under test
+const CHILD_ENV: &str = "HUB_CORE_SHUTDOWN_TEST_CHILD";
+
+/// Line printed by the service once its entry point is running
+const READY: &str = "shutdown-test: ready";
+
+/// Drain deadline passed to the service, in seconds
+const SHUTDOWN_TIMEOUT: u64 = 1;
+
+/// Upper bound on the time allowed for the service to exit after the deadline
+const EXIT_GRACE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, clap::Args)]
+struct Args {}
+
+fn main() {
+    if std::env::var_os(CHILD_ENV).is_some() {
+        holaplex_hub_core::run(start_config!("shutdown-test"), |_common, Args {}| {
+            println!("{READY}");
+
+            // Deliberately never check for a shutdown request
+            loop {
+                std::thread::sleep(Duration::from_secs(60));
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    entry_point_ignoring_shutdown_exits_after_deadline();
+}
+
+#[cfg(unix)]
+fn entry_point_ignoring_shutdown_exits_after_deadline() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+    // Kafka, credits, and the asset proxy must be configured when their
+    // features are enabled
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .arg("--shutdown-timeout")
+        .arg(SHUTDOWN_TIMEOUT.to_string())
+        .env(CHILD_ENV, "1")
+        .env("RUST_LOG", "off")
+        .env("KAFKA_BROKERS", "memory://")
+        .env("CREDIT_SHEET", manifest_dir.join("../../credits-test.toml"))
+        .env("ASSET_CDN", "https://example.com/")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    std::thread::spawn(move || {
+        for line in stdout.lines() {
+            if line.unwrap().trim() == READY {
+                tx.send(()).ok();
+            }
+        }
+    });
+
+    rx.recv_timeout(Duration::from_secs(30))
+        .expect("Service did not start");
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success(), "Failed to send SIGTERM to service");
+    let signaled = Instant::now();
+
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+
+        if signaled.elapsed() > Duration::from_secs(SHUTDOWN_TIMEOUT) + EXIT_GRACE {
+            child.kill().ok();
+            panic!("Service did not exit after the drain deadline");
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    };
+
+    assert!(
+        signaled.elapsed() >= Duration::from_secs(SHUTDOWN_TIMEOUT),
+        "Service exited before the drain deadline"
+    );
+    assert_eq!(
+        status.code(),
+        Some(holaplex_hub_core::triage::EXIT_UNKNOWN),
+        "Unexpected exit status {status}"
+    );
+}